@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::{Input, Stick};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickPolicy {
+    // the source pushing its stick the furthest wins
+    #[default]
+    MaxMagnitude,
+    // the highest priority source that moves its stick at all wins
+    Priority,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SourceConfig {
+    // higher wins ties and the priority stick policy
+    pub priority: i32,
+    // the whole input comes from this source alone while it holds anything or is active
+    pub exclusive: bool,
+    pub enabled: bool,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            exclusive: false,
+            enabled: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ArbiterConfig {
+    pub sticks: StickPolicy,
+    // by source name: tas, macro, keyboard, motion and the names of the gamepads
+    pub sources: HashMap<String, SourceConfig>,
+}
+
+impl Default for ArbiterConfig {
+    fn default() -> Self {
+        let source = |priority| SourceConfig { priority, ..SourceConfig::default() };
+        Self {
+            sticks: StickPolicy::default(),
+            sources: HashMap::from([
+                ("tas".to_string(), SourceConfig { exclusive: true, ..source(4) }),
+                ("macro".to_string(), SourceConfig { exclusive: true, ..source(3) }),
+                ("keyboard".to_string(), source(2)),
+                ("gamepad".to_string(), source(1)),
+                ("motion".to_string(), source(0)),
+            ]),
+        }
+    }
+}
+
+// one producer of input, it only ever writes its own copy
+pub struct InputSource {
+    name: String,
+    input: Arc<Mutex<Input>>,
+    config: SourceConfig,
+    // keeps an exclusive source in charge while it holds nothing, like a macro that waits
+    active: bool,
+}
+
+// combines the input of every source into what the controller reports
+#[derive(Clone)]
+pub struct Arbiter {
+    sources: Arc<Mutex<Vec<InputSource>>>,
+    sticks: StickPolicy,
+    configs: Arc<HashMap<String, SourceConfig>>,
+}
+
+impl Arbiter {
+    pub fn new(config: &ArbiterConfig) -> Self {
+        // sources left out of the config keep their default priority
+        let mut configs = ArbiterConfig::default().sources;
+        configs.extend(config.sources.clone());
+        Self {
+            sources: Arc::new(Mutex::new(vec![])),
+            sticks: config.sticks,
+            configs: Arc::new(configs),
+        }
+    }
+
+    // the input a new source writes to, sources sharing a name are switched together
+    pub fn register(&self, name: &str) -> Arc<Mutex<Input>> {
+        let input = Arc::new(Mutex::new(Input::new()));
+        let mut sources = self.sources.lock().unwrap();
+        sources.push(InputSource {
+            name: name.to_string(),
+            input: Arc::clone(&input),
+            config: self.configs.get(name).copied().unwrap_or_default(),
+            active: false,
+        });
+        // highest priority first
+        sources.sort_by_key(|s| -s.config.priority);
+        input
+    }
+
+    pub fn set_enabled(&self, name: &str, enabled: bool) {
+        let mut found = false;
+        for s in self.sources.lock().unwrap().iter_mut().filter(|s| s.name == name) {
+            s.config.enabled = enabled;
+            found = true;
+        }
+        if found {
+            println!("Input source {} {}", name, if enabled { "enabled" } else { "disabled" });
+        } else {
+            println!("No input source {}", name);
+        }
+    }
+
+    pub fn set_active(&self, name: &str, active: bool) {
+        for s in self.sources.lock().unwrap().iter_mut().filter(|s| s.name == name) {
+            s.active = active;
+        }
+    }
+
+    pub fn toggle(&self, name: &str) {
+        let enabled = self
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.config.enabled);
+        self.set_enabled(name, !enabled.unwrap_or(true));
+    }
+
+    // buttons held by any source, sticks by the stick policy, unless an exclusive source is in charge
+    pub fn combine(&self) -> Input {
+        let sources = self.sources.lock().unwrap();
+        let inputs: Vec<(Input, SourceConfig, bool)> = sources
+            .iter()
+            .filter(|s| s.config.enabled)
+            .map(|s| (s.input.lock().unwrap().clone(), s.config, s.active))
+            .collect();
+
+        if let Some((input, _, _)) = inputs.iter().find(|(i, c, active)| c.exclusive && (*active || !i.is_neutral())) {
+            return input.clone();
+        }
+
+        let mut combined = Input::new();
+        for (input, _, _) in &inputs {
+            combined.or_buttons(input);
+        }
+        combined.stick_l = self.pick(inputs.iter().map(|(i, _, _)| &i.stick_l), combined.stick_l.press);
+        combined.stick_r = self.pick(inputs.iter().map(|(i, _, _)| &i.stick_r), combined.stick_r.press);
+        combined
+    }
+
+    // `sticks` come highest priority first
+    fn pick<'a>(&self, sticks: impl Iterator<Item = &'a Stick>, press: bool) -> Stick {
+        let mut moved = sticks.filter(|s| s.magnitude() > 0.0);
+        let winner = match self.sticks {
+            StickPolicy::Priority => moved.next(),
+            // the first of equally long ones keeps the priority order
+            StickPolicy::MaxMagnitude => moved.fold(None, |best: Option<&Stick>, s| match best {
+                Some(b) if b.magnitude() >= s.magnitude() => Some(b),
+                _ => Some(s),
+            }),
+        };
+
+        Stick {
+            x: winner.map(|s| s.x).unwrap_or(0.0),
+            y: winner.map(|s| s.y).unwrap_or(0.0),
+            press,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputField;
+
+    fn arbiter(sticks: StickPolicy) -> Arbiter {
+        Arbiter::new(&ArbiterConfig { sticks, ..ArbiterConfig::default() })
+    }
+
+    fn set(input: &Arc<Mutex<Input>>, values: &[(InputField, f64)]) {
+        let mut input = input.lock().unwrap();
+        for (field, value) in values {
+            input.set(*field, *value);
+        }
+    }
+
+    fn stick_l(input: &Input) -> (f64, f64) {
+        (input.stick_l.x, input.stick_l.y)
+    }
+
+    #[test]
+    fn buttons_are_combined() {
+        let arbiter = arbiter(StickPolicy::MaxMagnitude);
+        let keyboard = arbiter.register("keyboard");
+        let gamepad = arbiter.register("gamepad");
+        set(&keyboard, &[(InputField::A, 1.0), (InputField::StickLPress, 1.0)]);
+        set(&gamepad, &[(InputField::B, 1.0)]);
+
+        let combined = arbiter.combine();
+        assert_eq!((combined.a, combined.b, combined.x, combined.stick_l.press), (true, true, false, true));
+    }
+
+    #[test]
+    fn furthest_stick_wins() {
+        let arbiter = arbiter(StickPolicy::MaxMagnitude);
+        let keyboard = arbiter.register("keyboard");
+        let motion = arbiter.register("motion");
+        set(&keyboard, &[(InputField::StickLX, 0.3)]);
+        set(&motion, &[(InputField::StickLX, -0.5), (InputField::StickLY, 0.5)]);
+        assert_eq!(stick_l(&arbiter.combine()), (-0.5, 0.5));
+
+        // equally far goes to the higher priority
+        set(&keyboard, &[(InputField::StickLX, 0.0), (InputField::StickLY, 0.5)]);
+        set(&motion, &[(InputField::StickLX, -0.5), (InputField::StickLY, 0.0)]);
+        assert_eq!(stick_l(&arbiter.combine()), (0.0, 0.5));
+    }
+
+    #[test]
+    fn highest_priority_stick_wins() {
+        let arbiter = arbiter(StickPolicy::Priority);
+        let motion = arbiter.register("motion");
+        let keyboard = arbiter.register("keyboard");
+        set(&keyboard, &[(InputField::StickLX, 0.3)]);
+        set(&motion, &[(InputField::StickLX, -1.0), (InputField::StickRY, 1.0)]);
+
+        let combined = arbiter.combine();
+        assert_eq!(stick_l(&combined), (0.3, 0.0));
+        // a centred stick leaves it to the next one
+        assert_eq!((combined.stick_r.x, combined.stick_r.y), (0.0, 1.0));
+    }
+
+    #[test]
+    fn exclusive_source_takes_over() {
+        let arbiter = arbiter(StickPolicy::MaxMagnitude);
+        let keyboard = arbiter.register("keyboard");
+        let tas = arbiter.register("tas");
+        set(&keyboard, &[(InputField::A, 1.0), (InputField::StickLX, 1.0)]);
+        assert!(arbiter.combine().a);
+
+        // while it holds anything
+        set(&tas, &[(InputField::B, 1.0)]);
+        let combined = arbiter.combine();
+        assert_eq!((combined.a, combined.b, stick_l(&combined)), (false, true, (0.0, 0.0)));
+
+        // or is active while holding nothing
+        set(&tas, &[(InputField::B, 0.0)]);
+        arbiter.set_active("tas", true);
+        assert!(arbiter.combine().is_neutral());
+
+        arbiter.set_active("tas", false);
+        let combined = arbiter.combine();
+        assert_eq!((combined.a, stick_l(&combined)), (true, (1.0, 0.0)));
+    }
+
+    #[test]
+    fn disabled_source_is_ignored() {
+        let arbiter = arbiter(StickPolicy::MaxMagnitude);
+        let gamepad = arbiter.register("gamepad");
+        let motion = arbiter.register("motion");
+        set(&gamepad, &[(InputField::X, 1.0), (InputField::StickLY, 0.2)]);
+        set(&motion, &[(InputField::Y, 1.0), (InputField::StickLY, -0.8)]);
+
+        arbiter.set_enabled("motion", false);
+        let combined = arbiter.combine();
+        assert_eq!((combined.x, combined.y, stick_l(&combined)), (true, false, (0.0, 0.2)));
+
+        // a disabled exclusive source doesn't take over either
+        let tas = arbiter.register("tas");
+        set(&tas, &[(InputField::B, 1.0)]);
+        arbiter.toggle("tas");
+        assert!(arbiter.combine().x);
+
+        arbiter.toggle("tas");
+        arbiter.toggle("motion");
+        assert!(arbiter.combine().b);
+        arbiter.set_active("tas", false);
+        set(&tas, &[(InputField::B, 0.0)]);
+        let combined = arbiter.combine();
+        assert_eq!((combined.x, combined.y, stick_l(&combined)), (true, true, (0.0, -0.8)));
+    }
+
+    #[test]
+    fn sources_sharing_a_name() {
+        let arbiter = arbiter(StickPolicy::MaxMagnitude);
+        let first = arbiter.register("gamepad");
+        let second = arbiter.register("gamepad");
+        set(&first, &[(InputField::A, 1.0)]);
+        set(&second, &[(InputField::B, 1.0)]);
+        arbiter.toggle("gamepad");
+        assert!(arbiter.combine().is_neutral());
+        arbiter.toggle("gamepad");
+        let combined = arbiter.combine();
+        assert_eq!((combined.a, combined.b), (true, true));
+    }
+}
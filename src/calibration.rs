@@ -0,0 +1,213 @@
+use crate::spi::Flash;
+
+// factory stick calibration, left then right, 9 bytes each
+const FACTORY_STICKS: u32 = 0x603d;
+// user stick calibration, each stick behind a 2 byte magic
+const USER_LEFT_STICK: u32 = 0x8010;
+const USER_RIGHT_OFFSET: usize = 0x0b;
+const USER_MAGIC: [u8; 2] = [0xb2, 0xa1];
+const STICK_SIZE: usize = 9;
+const USER_SIZE: usize = USER_RIGHT_OFFSET + USER_MAGIC.len() + STICK_SIZE;
+const RAW_MAX: f64 = 4095.0;
+
+// raw 12 bit values of one stick, the console maps center +- the ranges to full deflection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StickCalibration {
+    pub center: (u16, u16),
+    // distance from the center to the right and up
+    pub above: (u16, u16),
+    // distance from the center to the left and down
+    pub below: (u16, u16),
+}
+
+impl StickCalibration {
+    // what the controller uses without any calibration
+    pub fn linear() -> Self {
+        Self {
+            center: (2048, 2048),
+            above: (2047, 2047),
+            below: (2048, 2048),
+        }
+    }
+
+    // the left stick stores max, center, min, the right one center, min, max
+    fn parse(data: &[u8], left: bool) -> Self {
+        let v = unpack(data);
+        if left {
+            Self { above: (v[0], v[1]), center: (v[2], v[3]), below: (v[4], v[5]) }
+        } else {
+            Self { center: (v[0], v[1]), below: (v[2], v[3]), above: (v[4], v[5]) }
+        }
+    }
+
+    // deflection from -1 to 1 to raw values
+    pub fn encode(&self, x: f64, y: f64) -> (u16, u16) {
+        (
+            Self::encode_axis(x, self.center.0, self.above.0, self.below.0),
+            Self::encode_axis(y, self.center.1, self.above.1, self.below.1),
+        )
+    }
+
+    // raw values back to the deflection the console sees
+    pub fn decode(&self, x: u16, y: u16) -> (f64, f64) {
+        (
+            Self::decode_axis(x, self.center.0, self.above.0, self.below.0),
+            Self::decode_axis(y, self.center.1, self.above.1, self.below.1),
+        )
+    }
+
+    fn encode_axis(v: f64, center: u16, above: u16, below: u16) -> u16 {
+        let v = v.clamp(-1.0, 1.0);
+        let range = if v >= 0.0 { above } else { below };
+        (f64::from(center) + v * f64::from(range)).round().clamp(0.0, RAW_MAX) as u16
+    }
+
+    fn decode_axis(raw: u16, center: u16, above: u16, below: u16) -> f64 {
+        let offset = f64::from(raw) - f64::from(center);
+        let range = if offset >= 0.0 { above } else { below };
+        if range == 0 {
+            return 0.0;
+        }
+        (offset / f64::from(range)).clamp(-1.0, 1.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    pub left: StickCalibration,
+    pub right: StickCalibration,
+}
+
+impl Calibration {
+    // the user calibration where the console has written one, the factory one otherwise
+    pub fn from_flash(flash: &Flash) -> Self {
+        match (flash.read(USER_LEFT_STICK, USER_SIZE), flash.read(FACTORY_STICKS, STICK_SIZE * 2)) {
+            (Ok(user), Ok(factory)) => Self::parse(user, factory),
+            _ => Self { left: StickCalibration::linear(), right: StickCalibration::linear() },
+        }
+    }
+
+    // `user` as read from 0x8010, `factory` from 0x603d
+    pub fn parse(user: &[u8], factory: &[u8]) -> Self {
+        let stick = |user: &[u8], factory: &[u8], left: bool| {
+            if user.len() >= USER_MAGIC.len() + STICK_SIZE && user[..USER_MAGIC.len()] == USER_MAGIC {
+                return StickCalibration::parse(&user[USER_MAGIC.len()..], left);
+            }
+            if factory.len() >= STICK_SIZE && factory[..STICK_SIZE].iter().any(|b| *b != 0xff) {
+                return StickCalibration::parse(factory, left);
+            }
+            StickCalibration::linear()
+        };
+
+        let right_user = user.get(USER_RIGHT_OFFSET..).unwrap_or_default();
+        let right_factory = factory.get(STICK_SIZE..).unwrap_or_default();
+        Self {
+            left: stick(user, factory, true),
+            right: stick(right_user, right_factory, false),
+        }
+    }
+}
+
+// raw x and y of a stick as packed into the input reports
+pub fn unpack_stick(data: &[u8]) -> (u16, u16) {
+    let v = unpack(&[data[0], data[1], data[2], 0, 0, 0, 0, 0, 0]);
+    (v[0], v[1])
+}
+
+// six 12 bit values packed into 9 bytes
+fn unpack(data: &[u8]) -> [u16; 6] {
+    let mut v = [0u16; 6];
+    for i in 0..3 {
+        let b = &data[i * 3..i * 3 + 3];
+        v[i * 2] = (u16::from(b[1] & 0x0f) << 8) | u16::from(b[0]);
+        v[i * 2 + 1] = (u16::from(b[2]) << 4) | u16::from(b[1] >> 4);
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // six 12 bit values into 9 bytes, the way the controller stores them
+    fn pack(v: [u16; 6]) -> Vec<u8> {
+        v.chunks(2)
+            .flat_map(|p| [(p[0] & 0xff) as u8, ((p[0] >> 8) as u8 & 0x0f) | ((p[1] & 0x0f) as u8) << 4, (p[1] >> 4) as u8])
+            .collect()
+    }
+
+    fn round_trip(stick: &StickCalibration) {
+        for (x, y) in [(0.0, 0.0), (1.0, -1.0), (-1.0, 1.0), (0.5, -0.25), (-0.75, 0.1)] {
+            let (rx, ry) = stick.encode(x, y);
+            let (dx, dy) = stick.decode(rx, ry);
+            // one raw step is well under a percent of either range
+            assert!((dx - x).abs() < 0.002 && (dy - y).abs() < 0.002, "{:?} {:?} -> {:?}", stick, (x, y), (dx, dy));
+        }
+    }
+
+    #[test]
+    fn packing() {
+        let v = [0x123, 0xabc, 0x000, 0xfff, 0x800, 0x7ff];
+        assert_eq!(unpack(&pack(v)), v);
+        assert_eq!(unpack_stick(&pack(v)), (0x123, 0xabc));
+    }
+
+    #[test]
+    fn left_stick_layout() {
+        // max, center, min
+        let stick = StickCalibration::parse(&pack([0x5a0, 0x5b0, 0x810, 0x7f0, 0x5c0, 0x5d0]), true);
+        assert_eq!(stick, StickCalibration { above: (0x5a0, 0x5b0), center: (0x810, 0x7f0), below: (0x5c0, 0x5d0) });
+        assert_eq!(stick.encode(0.0, 0.0), (0x810, 0x7f0));
+        assert_eq!(stick.encode(1.0, -1.0), (0x810 + 0x5a0, 0x7f0 - 0x5d0));
+        round_trip(&stick);
+    }
+
+    #[test]
+    fn right_stick_layout() {
+        // center, min, max
+        let stick = StickCalibration::parse(&pack([0x800, 0x7e0, 0x600, 0x610, 0x620, 0x630]), false);
+        assert_eq!(stick, StickCalibration { center: (0x800, 0x7e0), below: (0x600, 0x610), above: (0x620, 0x630) });
+        assert_eq!(stick.encode(-1.0, 1.0), (0x800 - 0x600, 0x7e0 + 0x630));
+        round_trip(&stick);
+    }
+
+    #[test]
+    fn linear_round_trip() {
+        let stick = StickCalibration::linear();
+        assert_eq!(stick.encode(0.0, 0.0), (2048, 2048));
+        assert_eq!(stick.encode(1.0, -1.0), (4095, 0));
+        round_trip(&stick);
+    }
+
+    #[test]
+    fn user_calibration_overrides_factory() {
+        let mut flash = Flash::new();
+        let factory_left = pack([0x500, 0x500, 0x7f0, 0x800, 0x500, 0x500]);
+        let factory_right = pack([0x7f8, 0x808, 0x510, 0x510, 0x520, 0x520]);
+        flash.write(FACTORY_STICKS, &[factory_left, factory_right].concat()).unwrap();
+
+        // without the magic the factory calibration is used
+        let factory = Calibration::from_flash(&flash);
+        assert_eq!(factory.left.center, (0x7f0, 0x800));
+        assert_eq!(factory.right.center, (0x7f8, 0x808));
+
+        // the console writes a user calibration for the left stick only
+        let user_left = pack([0x600, 0x600, 0x820, 0x7e0, 0x600, 0x600]);
+        flash.write(USER_LEFT_STICK, &[&USER_MAGIC[..], &user_left].concat()).unwrap();
+        let calibration = Calibration::from_flash(&flash);
+        assert_eq!(calibration.left, StickCalibration { above: (0x600, 0x600), center: (0x820, 0x7e0), below: (0x600, 0x600) });
+        assert_eq!(calibration.right, factory.right);
+
+        // and then for the right one
+        let user_right = pack([0x810, 0x7f0, 0x580, 0x580, 0x590, 0x590]);
+        flash.write(USER_LEFT_STICK + USER_RIGHT_OFFSET as u32, &[&USER_MAGIC[..], &user_right].concat()).unwrap();
+        assert_eq!(Calibration::from_flash(&flash).right.center, (0x810, 0x7f0));
+    }
+
+    #[test]
+    fn erased_factory_calibration_is_linear() {
+        let calibration = Calibration::parse(&[0xff; USER_SIZE], &[0xff; STICK_SIZE * 2]);
+        assert_eq!(calibration.left, StickCalibration::linear());
+        assert_eq!(calibration.right, StickCalibration::linear());
+    }
+}
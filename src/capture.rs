@@ -0,0 +1,416 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::pcap;
+use crate::simulator::Pipe;
+
+// file header, the records follow directly
+const MAGIC: &[u8; 8] = b"MTKCAP01";
+// direction, timestamp in microseconds, length
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 2;
+// time the emulator gets to answer the last request of a replay
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    // record every report of the session into this file
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    FromHost,
+    ToHost,
+}
+
+impl Direction {
+    fn byte(&self) -> u8 {
+        match self {
+            Self::FromHost => 0x00,
+            Self::ToHost => 0x01,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    // since the start of the capture
+    pub time: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.flush()?;
+
+        Ok(Self {
+            out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let micros = self.start.elapsed().as_micros() as u64;
+        let len = data.len().min(usize::from(u16::MAX));
+
+        self.out.write_all(&[direction.byte()])?;
+        self.out.write_all(&micros.to_le_bytes())?;
+        self.out.write_all(&(len as u16).to_le_bytes())?;
+        self.out.write_all(&data[..len])?;
+        // keep the capture usable if we crash
+        self.out.flush()
+    }
+}
+
+// one half of a device whose traffic is written to a capture when there is a recorder
+pub struct Recorded<T> {
+    inner: T,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+}
+
+impl<T> Recorded<T> {
+    pub fn new(inner: T, recorder: Option<Arc<Mutex<Recorder>>>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<T: Read> Read for Recorded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let (Some(r), true) = (&self.recorder, n > 0) {
+            r.lock().unwrap().record(Direction::FromHost, &buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Recorded<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(r) = &self.recorder {
+            r.lock().unwrap().record(Direction::ToHost, &buf[..n])?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// our own captures and usbmon pcap or pcapng files
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Record>, Box<dyn Error>> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    if pcap::is_pcap(&data) {
+        return pcap::parse(&data);
+    }
+    if !data.starts_with(MAGIC) {
+        return Err(format!("{} is not a capture", path.display()).into());
+    }
+
+    let mut records = vec![];
+    let mut rest = &data[MAGIC.len()..];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_SIZE {
+            return Err(format!("{} is truncated", path.display()).into());
+        }
+
+        let direction = match rest[0] {
+            0x00 => Direction::FromHost,
+            0x01 => Direction::ToHost,
+            b => return Err(format!("invalid direction {:02X} in {}", b, path.display()).into()),
+        };
+        let micros = u64::from_le_bytes(rest[1..9].try_into().unwrap());
+        let len = usize::from(u16::from_le_bytes([rest[9], rest[10]]));
+        if rest.len() < RECORD_HEADER_SIZE + len {
+            return Err(format!("{} is truncated", path.display()).into());
+        }
+
+        records.push(Record {
+            time: Duration::from_micros(micros),
+            direction,
+            data: rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len].to_vec(),
+        });
+        rest = &rest[RECORD_HEADER_SIZE + len..];
+    }
+
+    Ok(records)
+}
+
+// feed the host side to the emulator with the original timing, returns both directions
+pub fn replay(mut pipe: Pipe, records: &[Record]) -> Vec<Record> {
+    let mut start = Instant::now();
+    let mut out = vec![];
+
+    // the controller announces itself before the console says anything, so the
+    // announcement can't be taken for the answer to the first request
+    let first = records.iter().position(|r| r.direction == Direction::FromHost).unwrap_or(records.len());
+    let announced = records[..first].iter().filter(|r| r.direction == Direction::ToHost).count();
+    let end = Instant::now() + SETTLE_TIME;
+    while out.len() < announced && Instant::now() < end {
+        receive(&mut pipe, start, &mut out);
+        thread::sleep(Duration::from_millis(1));
+    }
+    if let Some(r) = records.get(first) {
+        start = Instant::now().checked_sub(r.time).unwrap_or(start);
+    }
+
+    for r in records.iter().filter(|r| r.direction == Direction::FromHost) {
+        while start.elapsed() < r.time {
+            receive(&mut pipe, start, &mut out);
+            thread::sleep(Duration::from_millis(1));
+        }
+        if pipe.write_all(&r.data).is_err() {
+            break;
+        }
+        out.push(Record {
+            time: start.elapsed(),
+            direction: Direction::FromHost,
+            data: r.data.clone(),
+        });
+    }
+
+    let end = Instant::now() + SETTLE_TIME;
+    while Instant::now() < end {
+        receive(&mut pipe, start, &mut out);
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    out
+}
+
+fn receive(pipe: &mut Pipe, start: Instant, out: &mut Vec<Record>) {
+    let mut buf = [0u8; 512];
+    while let Ok(n) = pipe.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        out.push(Record {
+            time: start.elapsed(),
+            direction: Direction::ToHost,
+            data: buf[..n].to_vec(),
+        });
+    }
+}
+
+// a host request and the report that answered it
+#[derive(Debug)]
+pub struct Exchange {
+    pub request: Vec<u8>,
+    pub reply: Option<Vec<u8>>,
+}
+
+// pair every request that expects an answer with the first unused matching reply after it
+pub fn exchanges(records: &[Record]) -> Vec<Exchange> {
+    let mut used = vec![false; records.len()];
+    let mut exchanges = vec![];
+    for (i, r) in records.iter().enumerate() {
+        if r.direction != Direction::FromHost {
+            continue;
+        }
+        let key = match answer_key(&r.data) {
+            Some(k) => k,
+            None => continue,
+        };
+
+        let reply = (i + 1..records.len())
+            .find(|j| !used[*j] && records[*j].direction == Direction::ToHost && reply_key(&records[*j].data) == Some(key.clone()));
+        if let Some(j) = reply {
+            used[j] = true;
+        }
+        exchanges.push(Exchange {
+            request: r.data.clone(),
+            reply: reply.map(|j| records[j].data.clone()),
+        });
+    }
+    exchanges
+}
+
+// report id, command and for SPI reads the address and length of the reply a request expects
+fn answer_key(request: &[u8]) -> Option<Vec<u8>> {
+    match request {
+        [0x80, command @ (0x01..=0x03), ..] => Some(vec![0x81, *command]),
+        [0x01, rest @ ..] if rest.len() >= 15 && rest[9] == 0x10 => Some([&[0x21], &rest[9..15]].concat()),
+        [0x01, rest @ ..] if rest.len() >= 10 => Some(vec![0x21, rest[9]]),
+        _ => None,
+    }
+}
+
+fn reply_key(reply: &[u8]) -> Option<Vec<u8>> {
+    match reply {
+        [0x81, command, ..] => Some(vec![0x81, *command]),
+        [0x21, rest @ ..] if rest.len() >= 19 && rest[13] == 0x10 => Some([&[0x21], &rest[13..19]].concat()),
+        [0x21, rest @ ..] if rest.len() >= 14 => Some(vec![0x21, rest[13]]),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct Mismatch {
+    pub request: Vec<u8>,
+    pub expected: Option<Vec<u8>>,
+    pub actual: Option<Vec<u8>>,
+}
+
+impl Mismatch {
+    pub fn print(&self) {
+        match answer_key(&self.request).as_deref() {
+            Some([0x21, id, ..]) => println!("subcommand {:02X}:", id),
+            _ => println!("command {:02X?}:", &self.request[..2]),
+        }
+        match (&self.expected, &self.actual) {
+            (Some(_), Some(_)) => {
+                for (i, e, a) in self.bytes() {
+                    println!("  byte {:3}: expected {}, got {}", i, hex(e), hex(a));
+                }
+            }
+            (Some(e), None) => println!("  no reply, expected {:02X?}", e),
+            (None, Some(a)) => println!("  unexpected reply {:02X?}", a),
+            (None, None) => {}
+        }
+    }
+
+    // offset, expected and actual byte of every difference past the timer and input state
+    pub fn bytes(&self) -> Vec<(usize, Option<u8>, Option<u8>)> {
+        let (e, a) = match (&self.expected, &self.actual) {
+            (Some(e), Some(a)) => (e, a),
+            _ => return vec![],
+        };
+        let skip = if e.first() == Some(&0x21) { 13 } else { 0 };
+        (skip..e.len().max(a.len()))
+            .map(|i| (i, e.get(i).copied(), a.get(i).copied()))
+            .filter(|(_, e, a)| e != a)
+            .collect()
+    }
+}
+
+// compare the replies only, input reports depend on timing and the input state
+pub fn compare(expected: &[Record], actual: &[Record]) -> Vec<Mismatch> {
+    exchanges(expected)
+        .into_iter()
+        .zip(exchanges(actual))
+        .filter(|(e, a)| e.reply.as_deref().map(significant) != a.reply.as_deref().map(significant))
+        .map(|(e, a)| Mismatch {
+            request: e.request,
+            expected: e.reply,
+            actual: a.reply,
+        })
+        .collect()
+}
+
+fn hex(b: Option<u8>) -> String {
+    b.map(|b| format!("{:02X}", b)).unwrap_or_else(|| "--".to_string())
+}
+
+// a subcommand reply without its timer and input state, trailing padding ignored
+fn significant(data: &[u8]) -> &[u8] {
+    let data = match data.first() {
+        Some(0x21) => data.get(13..).unwrap_or_default(),
+        _ => data,
+    };
+    let end = data.iter().rposition(|b| *b != 0).map(|i| i + 1).unwrap_or(0);
+    &data[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.cap", name, std::process::id()))
+    }
+
+    fn record(direction: Direction, data: &[u8]) -> Record {
+        Record { time: Duration::ZERO, direction, data: data.to_vec() }
+    }
+
+    // a device info request and its reply
+    fn device_info(reply: &[u8]) -> Vec<Record> {
+        let mut request = vec![0x01, 0x00, 0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40, 0x02];
+        request.resize(64, 0x00);
+        let mut data = vec![0x21, 0x05, 0x81, 0x00, 0x00, 0x00, 0x00, 0x08, 0x80, 0x00, 0x08, 0x80, 0x00, 0x82, 0x02];
+        data.extend(reply);
+        data.resize(64, 0x00);
+        vec![record(Direction::FromHost, &request), record(Direction::ToHost, &data)]
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp("round-trip");
+        let written = [
+            (Direction::FromHost, vec![0x80, 0x01]),
+            (Direction::ToHost, vec![0x81, 0x01, 0x00, 0x03]),
+            (Direction::ToHost, vec![]),
+            (Direction::FromHost, vec![0x01; 64]),
+        ];
+        let mut recorder = Recorder::create(&path).unwrap();
+        for (direction, data) in &written {
+            recorder.record(*direction, data).unwrap();
+        }
+        drop(recorder);
+
+        let records = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let read: Vec<(Direction, Vec<u8>)> = records.iter().map(|r| (r.direction, r.data.clone())).collect();
+        assert_eq!(read, written);
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn broken_captures() {
+        let path = temp("broken");
+        let header = [&MAGIC[..], &[0x00], &5u64.to_le_bytes(), &4u16.to_le_bytes()].concat();
+        let check = |data: &[u8], error: &str| {
+            fs::write(&path, data).unwrap();
+            let e = load(&path).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{}", e);
+        };
+
+        check(&[&header[..], &[0x80, 0x01, 0x00]].concat(), &format!("{} is truncated", path.display()));
+        check(&header[..MAGIC.len() + 5], &format!("{} is truncated", path.display()));
+        let mut invalid = [&header[..], &[0x80, 0x01, 0x00, 0x00]].concat();
+        invalid[MAGIC.len()] = 0x02;
+        check(&invalid, "invalid direction 02");
+        check(b"MTKCAP00", &format!("{} is not a capture", path.display()));
+
+        fs::write(&path, [&header[..], &[0x80, 0x01, 0x00, 0x00]].concat()).unwrap();
+        assert_eq!(load(&path).unwrap().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_reply_byte() {
+        let info = [0x03, 0x48, 0x03, 0x02];
+        let expected = device_info(&info);
+        assert!(compare(&expected, &device_info(&info)).is_empty());
+
+        // the timer and the input state don't count
+        let mut later = device_info(&info);
+        later[1].data[1] = 0x42;
+        later[1].data[4] = 0x08;
+        assert!(compare(&expected, &later).is_empty());
+
+        let mismatches = compare(&expected, &device_info(&[0x03, 0x48, 0x01, 0x02]));
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].request, expected[0].data);
+        assert_eq!(mismatches[0].bytes(), [(17, Some(0x03), Some(0x01))]);
+
+        let mismatches = compare(&expected, &expected[..1]);
+        assert_eq!(mismatches[0].actual, None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+use crate::arbiter::ArbiterConfig;
+use crate::capture::CaptureConfig;
+use crate::gamepad::GamepadConfig;
+use crate::identity::Identity;
+use crate::imu::ImuConfig;
+use crate::keyboard::KeyboardConfig;
+use crate::link::LinkConfig;
+use crate::macros::MacroConfig;
+use crate::mapping::Rule;
+use crate::mcu::AmiiboConfig;
+use crate::pacing::PacingConfig;
+use crate::rumble::RumbleConfig;
+use crate::spi::FlashConfig;
+use crate::tas::TasConfig;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub mocopi: MocopiConfig,
+    pub identity: Identity,
+    pub imu: ImuConfig,
+    pub flash: FlashConfig,
+    pub amiibo: AmiiboConfig,
+    pub capture: CaptureConfig,
+    pub pacing: PacingConfig,
+    pub link: LinkConfig,
+    pub rumble: RumbleConfig,
+    pub keyboard: KeyboardConfig,
+    pub gamepads: Vec<GamepadConfig>,
+    pub arbiter: ArbiterConfig,
+    pub macros: MacroConfig,
+    pub tas: TasConfig,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct MocopiConfig {
+    pub port: u16,
+    pub csv: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mocopi: MocopiConfig::default(),
+            identity: Identity::default(),
+            imu: ImuConfig::default(),
+            flash: FlashConfig::default(),
+            amiibo: AmiiboConfig::default(),
+            capture: CaptureConfig::default(),
+            pacing: PacingConfig::default(),
+            link: LinkConfig::default(),
+            rumble: RumbleConfig::default(),
+            keyboard: KeyboardConfig::default(),
+            gamepads: vec![],
+            arbiter: ArbiterConfig::default(),
+            macros: MacroConfig::default(),
+            tas: TasConfig::default(),
+            rules: Rule::defaults(),
+        }
+    }
+}
+
+impl Default for MocopiConfig {
+    fn default() -> Self {
+        Self {
+            port: 12351,
+            csv: None,
+        }
+    }
+}
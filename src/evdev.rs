@@ -0,0 +1,142 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+
+// struct input_event on 64 bit: timeval, type, code, value
+const EVENT_SIZE: usize = 24;
+// _IOW('E', 0x90, int)
+const EVIOCGRAB: libc::c_ulong = 0x40044590;
+// _IOR('E', 0x40 + axis, struct input_absinfo)
+const EVIOCGABS: libc::c_ulong = 0x80184540;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    pub time: Duration,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl Event {
+    fn parse(buf: &[u8; EVENT_SIZE]) -> Self {
+        let secs = u64::from_ne_bytes(buf[0..8].try_into().unwrap());
+        let micros = u64::from_ne_bytes(buf[8..16].try_into().unwrap());
+        Self {
+            time: Duration::from_secs(secs) + Duration::from_micros(micros),
+            kind: u16::from_ne_bytes([buf[16], buf[17]]),
+            code: u16::from_ne_bytes([buf[18], buf[19]]),
+            value: i32::from_ne_bytes(buf[20..24].try_into().unwrap()),
+        }
+    }
+}
+
+// a live event device or a recording of one, made with e.g. `cat /dev/input/event3 > keys.ev`
+pub struct Events {
+    file: File,
+    // recordings are played back with their original timing
+    playback: Option<(Instant, Option<Duration>)>,
+}
+
+impl Events {
+    // live devices are grabbed so their events don't also reach the desktop or the terminal
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+
+        if !file.metadata()?.file_type().is_char_device() {
+            println!("Playing back input events from {}", path.display());
+            return Ok(Self { file, playback: Some((Instant::now(), None)) });
+        }
+
+        // SAFETY: EVIOCGRAB takes an int by value and the descriptor is open
+        if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGRAB, 1 as libc::c_int) } < 0 {
+            println!("Failed to grab {}: {}", path.display(), io::Error::last_os_error());
+        }
+        Ok(Self { file, playback: None })
+    }
+
+    // minimum and maximum of an axis, recordings don't know them
+    pub fn abs_range(&self, code: u16) -> Option<(i32, i32)> {
+        if self.playback.is_some() {
+            return None;
+        }
+        // value, minimum, maximum, fuzz, flat, resolution
+        let mut info = [0 as libc::c_int; 6];
+        // SAFETY: EVIOCGABS fills a struct input_absinfo, which is six ints
+        let r = unsafe { libc::ioctl(self.file.as_raw_fd(), EVIOCGABS + libc::c_ulong::from(code), info.as_mut_ptr()) };
+        (r >= 0 && info[1] < info[2]).then_some((info[1], info[2]))
+    }
+
+    // blocks until the next event, None at the end of a recording
+    pub fn next(&mut self) -> io::Result<Option<Event>> {
+        let mut buf = [0u8; EVENT_SIZE];
+        match self.file.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let event = Event::parse(&buf);
+
+        if let Some((start, first)) = &mut self.playback {
+            let first = *first.get_or_insert(event.time);
+            let due = *start + event.time.saturating_sub(first);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        Ok(Some(event))
+    }
+}
+
+// linux/input-event-codes.h names of the keys and buttons a mapping can use
+const CODES: &[(&str, u16)] = &[
+    ("KEY_ESC", 1), ("KEY_1", 2), ("KEY_2", 3), ("KEY_3", 4), ("KEY_4", 5), ("KEY_5", 6),
+    ("KEY_6", 7), ("KEY_7", 8), ("KEY_8", 9), ("KEY_9", 10), ("KEY_0", 11), ("KEY_MINUS", 12),
+    ("KEY_EQUAL", 13), ("KEY_BACKSPACE", 14), ("KEY_TAB", 15), ("KEY_Q", 16), ("KEY_W", 17),
+    ("KEY_E", 18), ("KEY_R", 19), ("KEY_T", 20), ("KEY_Y", 21), ("KEY_U", 22), ("KEY_I", 23),
+    ("KEY_O", 24), ("KEY_P", 25), ("KEY_LEFTBRACE", 26), ("KEY_RIGHTBRACE", 27), ("KEY_ENTER", 28),
+    ("KEY_LEFTCTRL", 29), ("KEY_A", 30), ("KEY_S", 31), ("KEY_D", 32), ("KEY_F", 33), ("KEY_G", 34),
+    ("KEY_H", 35), ("KEY_J", 36), ("KEY_K", 37), ("KEY_L", 38), ("KEY_SEMICOLON", 39),
+    ("KEY_APOSTROPHE", 40), ("KEY_GRAVE", 41), ("KEY_LEFTSHIFT", 42), ("KEY_BACKSLASH", 43),
+    ("KEY_Z", 44), ("KEY_X", 45), ("KEY_C", 46), ("KEY_V", 47), ("KEY_B", 48), ("KEY_N", 49),
+    ("KEY_M", 50), ("KEY_COMMA", 51), ("KEY_DOT", 52), ("KEY_SLASH", 53), ("KEY_RIGHTSHIFT", 54),
+    ("KEY_LEFTALT", 56), ("KEY_SPACE", 57), ("KEY_CAPSLOCK", 58), ("KEY_F1", 59), ("KEY_F2", 60),
+    ("KEY_F3", 61), ("KEY_F4", 62), ("KEY_F5", 63), ("KEY_F6", 64), ("KEY_F7", 65), ("KEY_F8", 66),
+    ("KEY_F9", 67), ("KEY_F10", 68), ("KEY_F11", 87), ("KEY_F12", 88), ("KEY_RIGHTCTRL", 97),
+    ("KEY_RIGHTALT", 100), ("KEY_HOME", 102), ("KEY_UP", 103), ("KEY_PAGEUP", 104), ("KEY_LEFT", 105),
+    ("KEY_RIGHT", 106), ("KEY_END", 107), ("KEY_DOWN", 108), ("KEY_PAGEDOWN", 109), ("KEY_INSERT", 110),
+    ("KEY_DELETE", 111),
+    ("BTN_SOUTH", 0x130), ("BTN_EAST", 0x131), ("BTN_C", 0x132), ("BTN_NORTH", 0x133),
+    ("BTN_WEST", 0x134), ("BTN_Z", 0x135), ("BTN_TL", 0x136), ("BTN_TR", 0x137), ("BTN_TL2", 0x138),
+    ("BTN_TR2", 0x139), ("BTN_SELECT", 0x13a), ("BTN_START", 0x13b), ("BTN_MODE", 0x13c),
+    ("BTN_THUMBL", 0x13d), ("BTN_THUMBR", 0x13e), ("BTN_DPAD_UP", 0x220), ("BTN_DPAD_DOWN", 0x221),
+    ("BTN_DPAD_LEFT", 0x222), ("BTN_DPAD_RIGHT", 0x223),
+];
+
+const ABS_CODES: &[(&str, u16)] = &[
+    ("ABS_X", 0x00), ("ABS_Y", 0x01), ("ABS_Z", 0x02), ("ABS_RX", 0x03), ("ABS_RY", 0x04),
+    ("ABS_RZ", 0x05), ("ABS_THROTTLE", 0x06), ("ABS_RUDDER", 0x07), ("ABS_WHEEL", 0x08),
+    ("ABS_GAS", 0x09), ("ABS_BRAKE", 0x0a), ("ABS_HAT0X", 0x10), ("ABS_HAT0Y", 0x11),
+];
+
+// a name from the table or the number of the code
+pub fn key_code(name: &str) -> Result<u16, Box<dyn Error>> {
+    lookup(CODES, name).ok_or_else(|| format!("unknown key {}", name).into())
+}
+
+pub fn abs_code(name: &str) -> Result<u16, Box<dyn Error>> {
+    lookup(ABS_CODES, name).ok_or_else(|| format!("unknown axis {}", name).into())
+}
+
+fn lookup(codes: &[(&str, u16)], name: &str) -> Option<u16> {
+    match codes.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        Some((_, code)) => Some(*code),
+        None => name.parse().ok(),
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+use crate::protocol::ReportMode;
+
+// player numbers by the pattern of the four player lights, 1 to 8
+const PLAYER_LIGHTS: [u8; 8] = [0b0001, 0b0011, 0b0111, 0b1111, 0b1001, 0b1010, 0b1011, 0b0110];
+// timing and 15 cycles of intensities, the rest of the report is padding
+pub const HOME_LIGHT_SIZE: usize = 25;
+
+// what the host has told the controller to show or do
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Feedback {
+    // subcommand 0x30, low nibble on, high nibble flashing
+    pub player_lights: u8,
+    // subcommand 0x38, the raw pattern
+    pub home_light: Vec<u8>,
+    // subcommand 0x40
+    pub imu: bool,
+    // subcommand 0x48
+    pub vibration: bool,
+    // subcommand 0x03
+    pub report_mode: ReportMode,
+    // subcommand 0x08
+    pub shipment_mode: bool,
+}
+
+impl Feedback {
+    // the player the lights show, none while they are off or still flashing to pair
+    pub fn player(&self) -> Option<u8> {
+        let on = self.player_lights & 0x0f;
+        PLAYER_LIGHTS.iter().position(|p| *p == on).map(|i| i as u8 + 1)
+    }
+
+    // brightness the HOME light starts at, 0 to 15
+    pub fn home_light_intensity(&self) -> u8 {
+        self.home_light.get(1).map(|b| b >> 4).unwrap_or(0)
+    }
+}
+
+impl Default for Feedback {
+    fn default() -> Self {
+        Self {
+            player_lights: 0x00,
+            home_light: vec![],
+            imu: false,
+            vibration: false,
+            report_mode: ReportMode::Standard,
+            shipment_mode: false,
+        }
+    }
+}
+
+// shared by everything that wants to know, updated by the subcommands
+#[derive(Clone)]
+pub struct ControllerFeedback {
+    sender: Arc<watch::Sender<Feedback>>,
+}
+
+impl ControllerFeedback {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(Feedback::default());
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn get(&self) -> Feedback {
+        self.sender.borrow().clone()
+    }
+
+    pub fn report_mode(&self) -> ReportMode {
+        self.sender.borrow().report_mode
+    }
+
+    // subscribers only wake up when something actually changed
+    pub fn update(&self, f: impl FnOnce(&mut Feedback)) {
+        self.sender.send_if_modified(|feedback| {
+            let before = feedback.clone();
+            f(feedback);
+            *feedback != before
+        });
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Feedback> {
+        self.sender.subscribe()
+    }
+}
+
+// print what changed whenever the host changes something
+pub fn start_logging(feedback: &ControllerFeedback) {
+    let mut receiver = feedback.subscribe();
+    tokio::task::spawn(async move {
+        let mut last = receiver.borrow().clone();
+        while receiver.changed().await.is_ok() {
+            let current = receiver.borrow().clone();
+            if current.player_lights != last.player_lights {
+                match current.player() {
+                    Some(p) => println!("Player {}", p),
+                    None => println!("Player lights: {:08b}", current.player_lights),
+                }
+            }
+            if current.home_light != last.home_light {
+                println!("HOME light: intensity {}", current.home_light_intensity());
+            }
+            if current.imu != last.imu {
+                println!("IMU {}", if current.imu { "on" } else { "off" });
+            }
+            if current.vibration != last.vibration {
+                println!("Vibration {}", if current.vibration { "on" } else { "off" });
+            }
+            if current.report_mode != last.report_mode {
+                println!("Report mode: {:02X} {:?}", current.report_mode.id(), current.report_mode);
+            }
+            if current.shipment_mode != last.shipment_mode {
+                println!("Shipment mode {}", if current.shipment_mode { "on" } else { "off" });
+            }
+            last = current;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lights(player_lights: u8) -> Feedback {
+        Feedback { player_lights, ..Feedback::default() }
+    }
+
+    #[test]
+    fn players() {
+        let players: Vec<Option<u8>> = PLAYER_LIGHTS.iter().map(|p| lights(*p).player()).collect();
+        assert_eq!(players, (1..=8).map(Some).collect::<Vec<_>>());
+        // players 5 to 8 mix the lights rather than count them
+        assert_eq!(lights(0b1001).player(), Some(5));
+        assert_eq!(lights(0b0110).player(), Some(8));
+
+        // off, flashing while pairing, or no known pattern
+        assert_eq!(lights(0x00).player(), None);
+        assert_eq!(lights(0xf0).player(), None);
+        assert_eq!(lights(0b0101).player(), None);
+        // only the lights that are on count
+        assert_eq!(lights(0x13).player(), Some(2));
+    }
+
+    #[test]
+    fn home_light_intensity() {
+        let home = |home_light: Vec<u8>| Feedback { home_light, ..Feedback::default() }.home_light_intensity();
+        assert_eq!(home(vec![]), 0);
+        assert_eq!(home(vec![0x18]), 0);
+        assert_eq!(home(vec![0x18, 0xf1, 0x00]), 15);
+        assert_eq!(home(vec![0x18, 0x81, 0x00]), 8);
+    }
+
+    #[test]
+    fn only_changes_notify() {
+        let feedback = ControllerFeedback::new();
+        let mut receiver = feedback.subscribe();
+
+        feedback.update(|f| f.player_lights = 0x00);
+        assert!(!receiver.has_changed().unwrap());
+
+        feedback.update(|f| f.player_lights = 0x01);
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(receiver.borrow_and_update().player(), Some(1));
+        assert_eq!(feedback.get().player_lights, 0x01);
+    }
+}
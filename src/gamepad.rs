@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::{Input, InputField};
+use crate::evdev::{self, Event, Events, EV_ABS, EV_KEY};
+use crate::lifecycle::Shutdown;
+
+// how far a trigger or hat has to go to count as pressed
+const PRESS_THRESHOLD: f64 = 0.5;
+
+#[derive(Deserialize)]
+pub struct GamepadConfig {
+    // /dev/input/by-id/...-event-joystick or a recording of one
+    pub device: String,
+    // input source name to give it a priority or switch it off
+    #[serde(default = "GamepadConfig::default_name")]
+    pub name: String,
+    #[serde(default = "GamepadConfig::default_buttons")]
+    pub buttons: Vec<ButtonBinding>,
+    #[serde(default = "GamepadConfig::default_axes")]
+    pub axes: Vec<AxisBinding>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ButtonBinding {
+    // BTN_* name or code
+    pub code: String,
+    pub target: InputField,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AxisBinding {
+    // ABS_* name or code
+    pub code: String,
+    // a stick axis, or a button pressed past half way
+    pub target: InputField,
+    // button for the other direction, which makes the axis centred like a hat
+    pub negative: Option<InputField>,
+    #[serde(default)]
+    pub invert: bool,
+    // taken from the device when left out, recordings need them for other than the usual ranges
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+    // part of the stick range around the centre that reads as centred
+    #[serde(default = "AxisBinding::default_deadzone")]
+    pub deadzone: f64,
+}
+
+impl AxisBinding {
+    fn default_deadzone() -> f64 {
+        0.1
+    }
+
+    fn new(code: &str, target: InputField, negative: Option<InputField>, invert: bool) -> Self {
+        Self {
+            code: code.to_string(),
+            target,
+            negative,
+            invert,
+            min: None,
+            max: None,
+            deadzone: Self::default_deadzone(),
+        }
+    }
+
+    fn is_centred(&self) -> bool {
+        self.target.is_axis() || self.negative.is_some()
+    }
+
+    // -1 to 1 for centred axes, 0 to 1 for triggers
+    fn normalize(&self, value: i32, (min, max): (i32, i32)) -> f64 {
+        let t = (f64::from(value) - f64::from(min)) / f64::from(max - min).max(1.0);
+        let t = if self.invert { 1.0 - t } else { t }.clamp(0.0, 1.0);
+        if !self.is_centred() {
+            return t;
+        }
+
+        let v = t * 2.0 - 1.0;
+        if v.abs() < self.deadzone {
+            return 0.0;
+        }
+        // the deadzone is cut out so the stick still reaches full deflection
+        v.signum() * (v.abs() - self.deadzone) / (1.0 - self.deadzone)
+    }
+}
+
+impl GamepadConfig {
+    fn default_name() -> String {
+        "gamepad".to_string()
+    }
+
+    // a typical Linux gamepad, the face buttons keep their position rather than their label
+    fn default_buttons() -> Vec<ButtonBinding> {
+        use InputField::*;
+        [
+            ("BTN_EAST", A),
+            ("BTN_SOUTH", B),
+            ("BTN_NORTH", X),
+            ("BTN_WEST", Y),
+            ("BTN_TL", L),
+            ("BTN_TR", R),
+            ("BTN_TL2", Zl),
+            ("BTN_TR2", Zr),
+            ("BTN_SELECT", Minus),
+            ("BTN_START", Plus),
+            ("BTN_MODE", Home),
+            ("BTN_THUMBL", StickLPress),
+            ("BTN_THUMBR", StickRPress),
+            ("BTN_DPAD_UP", Up),
+            ("BTN_DPAD_DOWN", Down),
+            ("BTN_DPAD_LEFT", Left),
+            ("BTN_DPAD_RIGHT", Right),
+        ]
+        .into_iter()
+        .map(|(code, target)| ButtonBinding { code: code.to_string(), target })
+        .collect()
+    }
+
+    // evdev axes grow to the right and down, the console's sticks up; ZL and ZR come from BTN_TL2 and
+    // BTN_TR2 only, ABS_Z and ABS_RZ are analog triggers on some pads and the right stick on others
+    fn default_axes() -> Vec<AxisBinding> {
+        use InputField::*;
+        vec![
+            AxisBinding::new("ABS_X", StickLX, None, false),
+            AxisBinding::new("ABS_Y", StickLY, None, true),
+            AxisBinding::new("ABS_RX", StickRX, None, false),
+            AxisBinding::new("ABS_RY", StickRY, None, true),
+            AxisBinding::new("ABS_HAT0X", Right, Some(Left), false),
+            AxisBinding::new("ABS_HAT0Y", Down, Some(Up), false),
+        ]
+    }
+}
+
+// ranges of the usual axes when the device can't be asked
+fn fallback_range(code: u16) -> (i32, i32) {
+    match code {
+        // ABS_Z, ABS_RZ, ABS_GAS and ABS_BRAKE are usually analog triggers
+        0x02 | 0x05 | 0x09 | 0x0a => (0, 255),
+        0x10..=0x17 => (-1, 1),
+        _ => (-32768, 32767),
+    }
+}
+
+struct Axis {
+    binding: AxisBinding,
+    range: (i32, i32),
+    // where the binding keeps its value, and the one of its negative direction
+    slot: usize,
+    negative_slot: Option<usize>,
+}
+
+// the gamepad's events onto the input
+struct PadMap {
+    buttons: HashMap<u16, Vec<usize>>,
+    axes: HashMap<u16, Vec<Axis>>,
+    // every binding's own value, fields with several bindings combine them
+    slots: Vec<(InputField, f64)>,
+}
+
+impl PadMap {
+    fn new(config: &GamepadConfig, events: &Events) -> Result<Self, Box<dyn Error>> {
+        let mut slots = vec![];
+        let mut slot = |field| {
+            slots.push((field, 0.0));
+            slots.len() - 1
+        };
+
+        let mut buttons: HashMap<u16, Vec<usize>> = HashMap::new();
+        for b in &config.buttons {
+            buttons.entry(evdev::key_code(&b.code)?).or_default().push(slot(b.target));
+        }
+        let mut axes: HashMap<u16, Vec<Axis>> = HashMap::new();
+        for a in &config.axes {
+            let code = evdev::abs_code(&a.code)?;
+            let device = events.abs_range(code).unwrap_or_else(|| fallback_range(code));
+            let range = (a.min.unwrap_or(device.0), a.max.unwrap_or(device.1));
+            axes.entry(code).or_default().push(Axis {
+                binding: a.clone(),
+                range,
+                slot: slot(a.target),
+                negative_slot: a.negative.map(&mut slot),
+            });
+        }
+        Ok(Self { buttons, axes, slots })
+    }
+
+    fn apply(&mut self, event: &Event, input: &mut Input) {
+        let mut changed = vec![];
+        match event.kind {
+            EV_KEY if event.value != 2 => {
+                for slot in self.buttons.get(&event.code).into_iter().flatten() {
+                    changed.push((*slot, f64::from(event.value)));
+                }
+            }
+            EV_ABS => {
+                for axis in self.axes.get(&event.code).into_iter().flatten() {
+                    let b = &axis.binding;
+                    let v = b.normalize(event.value, axis.range);
+                    if b.target.is_axis() {
+                        changed.push((axis.slot, v));
+                    } else {
+                        changed.push((axis.slot, f64::from(u8::from(v > PRESS_THRESHOLD))));
+                        if let Some(negative) = axis.negative_slot {
+                            changed.push((negative, f64::from(u8::from(v < -PRESS_THRESHOLD))));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        for (slot, value) in changed {
+            self.slots[slot].1 = value;
+            let field = self.slots[slot].0;
+            input.set(field, self.value(field));
+        }
+    }
+
+    // buttons are held while any of their bindings is, sticks follow the one pushed furthest
+    fn value(&self, field: InputField) -> f64 {
+        let mut values = self.slots.iter().filter(|(f, _)| *f == field).map(|(_, v)| *v);
+        if field.is_axis() {
+            values.fold(0.0, |a, v| if v.abs() > a.abs() { v } else { a })
+        } else {
+            f64::from(u8::from(values.any(|v| v != 0.0)))
+        }
+    }
+
+    // let go of everything the gamepad was holding
+    fn release_all(&mut self, input: &mut Input) {
+        for (field, value) in &mut self.slots {
+            *value = 0.0;
+            input.set(*field, 0.0);
+        }
+    }
+}
+
+// copy the buttons and sticks of a gamepad onto the input
+pub fn start(config: &GamepadConfig, input: Arc<Mutex<Input>>, shutdown: Shutdown) -> Result<(), Box<dyn Error>> {
+    let mut events = Events::open(&config.device)?;
+    let mut map = PadMap::new(config, &events)?;
+
+    let device = config.device.clone();
+    tokio::task::spawn_blocking(move || {
+        println!("start gamepad input from {}", device);
+        while !shutdown.is_stopped() {
+            match events.next() {
+                Ok(Some(e)) => map.apply(&e, &mut input.lock().unwrap()),
+                Ok(None) => break,
+                Err(e) => {
+                    println!("Failed to read the gamepad {}: {}", device, e);
+                    break;
+                }
+            }
+        }
+
+        map.release_all(&mut input.lock().unwrap());
+        println!("end gamepad input from {}", device);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pad.ev");
+
+    fn config() -> GamepadConfig {
+        GamepadConfig {
+            device: FIXTURE.to_string(),
+            name: GamepadConfig::default_name(),
+            buttons: GamepadConfig::default_buttons(),
+            axes: GamepadConfig::default_axes(),
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // the left stick, A, up and right after every event of the recording
+    #[test]
+    fn recorded_gamepad() {
+        let config = config();
+        let mut events = Events::open(&config.device).unwrap();
+        let mut map = PadMap::new(&config, &events).unwrap();
+        let mut input = Input::new();
+        let mut states = vec![];
+        while let Some(event) = events.next().unwrap() {
+            if event.kind == EV_KEY || event.kind == EV_ABS {
+                map.apply(&event, &mut input);
+                states.push((input.stick_l.x, input.stick_l.y, input.a, input.up, input.right));
+            }
+        }
+
+        assert_eq!(
+            states,
+            [
+                (1.0, 0.0, false, false, false),
+                // evdev's up is the console's down
+                (1.0, 1.0, false, false, false),
+                // inside the deadzone
+                (0.0, 1.0, false, false, false),
+                (0.0, 1.0, true, false, false),
+                // the hat's negative direction
+                (0.0, 1.0, true, true, false),
+                (0.0, 1.0, true, false, false),
+                (0.0, 1.0, true, false, true),
+                (0.0, 1.0, false, false, true),
+                (0.0, 0.0, false, false, true),
+            ]
+        );
+
+        map.release_all(&mut input);
+        assert!(input.is_neutral());
+    }
+
+    #[test]
+    fn deadzone_is_cut_out() {
+        let b = AxisBinding::new("ABS_X", InputField::StickLX, None, false);
+        let range = (-100, 100);
+        assert_eq!(b.normalize(5, range), 0.0);
+        assert_eq!(b.normalize(-5, range), 0.0);
+        // just outside the deadzone starts from zero again, the ends still reach full deflection
+        assert!(close(b.normalize(55, range), (0.55 - 0.1) / 0.9));
+        assert!(close(b.normalize(100, range), 1.0));
+        assert!(close(b.normalize(-100, range), -1.0));
+        // beyond the range is clamped
+        assert!(close(b.normalize(300, range), 1.0));
+    }
+
+    #[test]
+    fn inverted_axis() {
+        let b = AxisBinding::new("ABS_Y", InputField::StickLY, None, true);
+        assert!(close(b.normalize(-32768, (-32768, 32767)), 1.0));
+        assert!(close(b.normalize(32767, (-32768, 32767)), -1.0));
+    }
+
+    #[test]
+    fn trigger_is_not_centred() {
+        let b = AxisBinding::new("ABS_Z", InputField::Zl, None, false);
+        assert_eq!(b.normalize(0, (0, 255)), 0.0);
+        // no deadzone around the middle of a trigger
+        assert!(close(b.normalize(51, (0, 255)), 0.2));
+        assert_eq!(b.normalize(255, (0, 255)), 1.0);
+    }
+
+    // a pad reporting ZL as a button and an analog trigger at the same time
+    #[test]
+    fn bindings_of_one_field_are_combined() {
+        let config = GamepadConfig {
+            buttons: vec![ButtonBinding { code: "BTN_TL2".to_string(), target: InputField::Zl }],
+            axes: vec![
+                AxisBinding::new("ABS_Z", InputField::Zl, None, false),
+                AxisBinding::new("ABS_X", InputField::StickLX, None, false),
+                AxisBinding::new("ABS_RX", InputField::StickLX, None, false),
+            ],
+            ..config()
+        };
+        let mut map = PadMap::new(&config, &Events::open(FIXTURE).unwrap()).unwrap();
+        let mut input = Input::new();
+        let event = |kind, code, value| Event { time: Duration::ZERO, kind, code, value };
+
+        map.apply(&event(EV_KEY, 0x138, 1), &mut input);
+        assert!(input.zl);
+        // the trigger starting to move doesn't let go of the held button
+        map.apply(&event(EV_ABS, 0x02, 100), &mut input);
+        assert!(input.zl);
+        map.apply(&event(EV_ABS, 0x02, 200), &mut input);
+        map.apply(&event(EV_KEY, 0x138, 0), &mut input);
+        assert!(input.zl);
+        map.apply(&event(EV_ABS, 0x02, 0), &mut input);
+        assert!(!input.zl);
+
+        // the stick pushed furthest wins
+        map.apply(&event(EV_ABS, 0x00, 32767), &mut input);
+        map.apply(&event(EV_ABS, 0x03, -16384), &mut input);
+        assert!(close(input.stick_l.x, 1.0));
+        map.apply(&event(EV_ABS, 0x00, 0), &mut input);
+        assert!(input.stick_l.x < -0.3);
+
+        map.release_all(&mut input);
+        assert!(input.is_neutral());
+    }
+
+    #[test]
+    fn hat_with_negative() {
+        let b = AxisBinding::new("ABS_HAT0X", InputField::Right, Some(InputField::Left), false);
+        assert_eq!(b.normalize(-1, (-1, 1)), -1.0);
+        assert_eq!(b.normalize(0, (-1, 1)), 0.0);
+        assert_eq!(b.normalize(1, (-1, 1)), 1.0);
+    }
+}
@@ -0,0 +1,322 @@
+use std::error::Error;
+use std::fmt;
+use serde::Deserialize;
+use crate::spi::{self, Flash};
+
+const SERIAL_ADDR: u32 = 0x6000;
+const DEVICE_TYPE_ADDR: u32 = 0x6012;
+const COLOR_INFO_ADDR: u32 = 0x601b;
+const COLOR_ADDR: u32 = 0x6050;
+const FACTORY_STICK_L_ADDR: u32 = 0x603d;
+const FACTORY_STICK_R_ADDR: u32 = 0x6046;
+const USER_STICK_L_ADDR: u32 = 0x8010;
+const USER_STICK_R_ADDR: u32 = 0x801b;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    Pro,
+    Left,
+    Right,
+}
+
+impl DeviceType {
+    pub fn byte(&self) -> u8 {
+        match self {
+            Self::Left => 0x01,
+            Self::Right => 0x02,
+            Self::Pro => 0x03,
+        }
+    }
+
+    // battery level and connection info, the first byte of every input report
+    pub fn connection_info(&self) -> u8 {
+        match self {
+            Self::Pro => 0x81,
+            Self::Left | Self::Right => 0x8e,
+        }
+    }
+}
+
+// how a single Joy-Con is held, the Pro Controller is always vertical
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    #[default]
+    Vertical,
+    Sideways,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MacAddress(pub [u8; 6]);
+
+impl TryFrom<String> for MacAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 6 {
+            return Err(format!("invalid MAC address: {}", s));
+        }
+
+        let mut mac = [0u8; 6];
+        for (b, p) in mac.iter_mut().zip(parts) {
+            // from_str_radix would also take a sign
+            if !p.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("invalid MAC address: {}", s));
+            }
+            *b = u8::from_str_radix(p, 16).map_err(|_| format!("invalid MAC address: {}", s))?;
+        }
+        Ok(Self(mac))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let hex = s.trim_start_matches('#');
+        let value = match u32::from_str_radix(hex, 16) {
+            Ok(v) if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) => v,
+            _ => return Err(format!("invalid color: {}", s)),
+        };
+        let b = value.to_be_bytes();
+        Ok(Self([b[1], b[2], b[3]]))
+    }
+}
+
+// the serial and the colors are only written to flash when they are set,
+// so those of an image dumped from a real controller are kept
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Identity {
+    pub mac: MacAddress,
+    // up to 16 ASCII characters, empty for none
+    pub serial: Option<String>,
+    pub firmware: [u8; 2],
+    pub device_type: DeviceType,
+    pub orientation: Orientation,
+    pub body_color: Option<Color>,
+    pub button_color: Option<Color>,
+    pub left_grip_color: Option<Color>,
+    pub right_grip_color: Option<Color>,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self {
+            mac: MacAddress([0x5e, 0x53, 0x00, 0x5e, 0x00, 0x00]),
+            serial: None,
+            firmware: [0x03, 0x48],
+            device_type: DeviceType::Pro,
+            orientation: Orientation::Vertical,
+            body_color: None,
+            button_color: None,
+            left_grip_color: None,
+            right_grip_color: None,
+        }
+    }
+}
+
+impl Identity {
+    // reply to the 0x80 0x01 status request, the MAC is little endian
+    pub fn status(&self) -> [u8; 8] {
+        let m = self.mac.0;
+        [0x00, self.device_type.byte(), m[5], m[4], m[3], m[2], m[1], m[0]]
+    }
+
+    // reply to subcommand 0x02
+    pub fn device_info(&self) -> [u8; 12] {
+        let m = self.mac.0;
+        [
+            self.firmware[0],
+            self.firmware[1],
+            self.device_type.byte(),
+            0x02,
+            m[0],
+            m[1],
+            m[2],
+            m[3],
+            m[4],
+            m[5],
+            0x03,
+            0x01,
+        ]
+    }
+
+    // write what is configured about the controller into flash
+    pub fn apply(&self, flash: &mut Flash) -> Result<(), Box<dyn Error>> {
+        // saved to the image in one go
+        let mut writes = vec![];
+
+        if let Some(s) = &self.serial {
+            if s.len() > 16 || !s.is_ascii() {
+                return Err(format!("invalid serial number: {}", s).into());
+            }
+            let mut serial = [0xffu8; 16];
+            if !s.is_empty() {
+                serial = [0x00; 16];
+                serial[..s.len()].copy_from_slice(s.as_bytes());
+            }
+            writes.push((SERIAL_ADDR, serial.to_vec()));
+        }
+
+        // the console has to find the type the controller reports itself as
+        if flash.read(DEVICE_TYPE_ADDR, 1)? != [self.device_type.byte()] {
+            writes.push((DEVICE_TYPE_ADDR, vec![self.device_type.byte()]));
+        }
+
+        let colors = [self.body_color, self.button_color, self.left_grip_color, self.right_grip_color];
+        for (i, c) in colors.iter().enumerate() {
+            if let Some(c) = c {
+                writes.push((COLOR_ADDR + 3 * i as u32, c.0.to_vec()));
+            }
+        }
+        if colors.iter().any(Option::is_some) {
+            // body, buttons and both grips are set
+            writes.push((COLOR_INFO_ADDR, vec![0x02]));
+        }
+
+        // a single Joy-Con only has calibration for its own stick, a new image has both
+        let has_left = self.device_type != DeviceType::Right;
+        let has_right = self.device_type != DeviceType::Left;
+        for (factory, user, present) in [
+            (FACTORY_STICK_L_ADDR, USER_STICK_L_ADDR, has_left),
+            (FACTORY_STICK_R_ADDR, USER_STICK_R_ADDR, has_right),
+        ] {
+            if present && flash.read(factory, 9)?.iter().all(|b| *b == 0xff) {
+                writes.push((factory, spi::default_contents(factory, 9)));
+            } else if !present && flash.is_new() {
+                writes.push((factory, vec![0xff; 9]));
+                writes.push((user, vec![0xff; 11]));
+            }
+        }
+
+        if writes.is_empty() {
+            return Ok(());
+        }
+        flash.write_batch(&writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FACTORY_LEFT: [u8; 9] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99];
+
+    fn image(path: &std::path::Path, edit: impl FnOnce(&mut Flash)) -> Flash {
+        let _ = std::fs::remove_file(path);
+        edit(&mut Flash::open(path).unwrap());
+        // loaded again like an existing dump
+        Flash::open(path).unwrap()
+    }
+
+    #[test]
+    fn device_types() {
+        let identity = |device_type| Identity { device_type, ..Identity::default() };
+        for (device, byte, connection) in [(DeviceType::Pro, 0x03, 0x81), (DeviceType::Left, 0x01, 0x8e), (DeviceType::Right, 0x02, 0x8e)] {
+            assert_eq!(identity(device).status()[1], byte);
+            assert_eq!(identity(device).device_info()[2], byte);
+            assert_eq!(device.connection_info(), connection);
+        }
+    }
+
+    #[test]
+    fn mac_addresses() {
+        let mac = MacAddress::try_from("5e:53:00:5E:0a:ff".to_string()).unwrap();
+        assert_eq!(mac, MacAddress([0x5e, 0x53, 0x00, 0x5e, 0x0a, 0xff]));
+        assert_eq!(mac.to_string(), "5e:53:00:5e:0a:ff");
+
+        for s in ["", "5e:53:00:5e:0a", "5e:53:00:5e:0a:ff:00", "5e:53:00:5e:0a:100", "5e:53:00:5e:0a:gg", "5e:53:00:5e:0a:+f", "5e-53-00-5e-0a-ff"] {
+            assert_eq!(MacAddress::try_from(s.to_string()), Err(format!("invalid MAC address: {}", s)));
+        }
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(Color::try_from("#0ab9e6".to_string()), Ok(Color([0x0a, 0xb9, 0xe6])));
+        assert_eq!(Color::try_from("FF3C28".to_string()), Ok(Color([0xff, 0x3c, 0x28])));
+
+        for s in ["", "#", "#abc", "#0ab9e6ff", "#0ab9eg", "#+ab9e6"] {
+            assert_eq!(Color::try_from(s.to_string()), Err(format!("invalid color: {}", s)));
+        }
+    }
+
+    #[test]
+    fn parsed_from_the_config() {
+        let identity: Identity = toml::from_str(r##"
+            mac = "00:11:22:33:44:55"
+            body_color = "#123456"
+        "##).unwrap();
+        assert_eq!(identity.mac, MacAddress([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]));
+        assert_eq!(identity.body_color, Some(Color([0x12, 0x34, 0x56])));
+        assert_eq!(identity.button_color, None);
+
+        let error = toml::from_str::<Identity>(r#"button_color = "blue""#).unwrap_err();
+        assert!(error.to_string().contains("invalid color: blue"), "{}", error);
+    }
+
+    #[test]
+    fn dumped_image_survives() {
+        let path = std::env::temp_dir().join(format!("identity-{}.bin", std::process::id()));
+        let mut flash = image(&path, |f| {
+            f.write(FACTORY_STICK_L_ADDR, &FACTORY_LEFT).unwrap();
+            f.write(SERIAL_ADDR, b"XKW10000000001\0\0").unwrap();
+            f.write(COLOR_ADDR, &[0x0a, 0xb9, 0xe6]).unwrap();
+        });
+        Identity::default().apply(&mut flash).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(flash.read(FACTORY_STICK_L_ADDR, 9).unwrap(), FACTORY_LEFT);
+        assert_eq!(flash.read(SERIAL_ADDR, 3).unwrap(), b"XKW");
+        assert_eq!(flash.read(COLOR_ADDR, 3).unwrap(), [0x0a, 0xb9, 0xe6]);
+    }
+
+    #[test]
+    fn set_fields_are_written() {
+        let mut flash = Flash::new();
+        let identity = Identity {
+            serial: Some("S1".to_string()),
+            device_type: DeviceType::Left,
+            button_color: Some(Color([0x01, 0x02, 0x03])),
+            ..Identity::default()
+        };
+        identity.apply(&mut flash).unwrap();
+
+        assert_eq!(flash.read(SERIAL_ADDR, 3).unwrap(), [b'S', b'1', 0x00]);
+        assert_eq!(flash.read(DEVICE_TYPE_ADDR, 1).unwrap(), [0x01]);
+        // the body color is left as it was
+        assert_eq!(flash.read(COLOR_ADDR, 6).unwrap(), [0x32, 0x32, 0x32, 0x01, 0x02, 0x03]);
+        // a new image loses the stick a left Joy-Con doesn't have
+        assert_ne!(flash.read(FACTORY_STICK_L_ADDR, 9).unwrap(), [0xff; 9]);
+        assert_eq!(flash.read(FACTORY_STICK_R_ADDR, 9).unwrap(), [0xff; 9]);
+
+        let long = Identity { serial: Some("X".repeat(17)), ..Identity::default() };
+        assert!(long.apply(&mut flash).is_err());
+    }
+
+    #[test]
+    fn erased_stick_calibration_is_seeded() {
+        let path = std::env::temp_dir().join(format!("identity-erased-{}.bin", std::process::id()));
+        let mut flash = image(&path, |f| f.erase(0x6000).unwrap());
+        Identity::default().apply(&mut flash).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(flash.read(FACTORY_STICK_L_ADDR, 9).unwrap(), spi::default_contents(FACTORY_STICK_L_ADDR, 9));
+        assert_eq!(flash.read(FACTORY_STICK_R_ADDR, 9).unwrap(), spi::default_contents(FACTORY_STICK_R_ADDR, 9));
+        assert_eq!(flash.read(DEVICE_TYPE_ADDR, 1).unwrap(), [0x03]);
+    }
+}
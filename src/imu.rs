@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use mocopi_parser::{BoneTrans, FramePacket};
+use serde::Deserialize;
+use crate::mocopi;
+use crate::quaternion::Quaternion;
+
+pub const BONE_RIGHT_HAND: u16 = 18;
+
+// raw units per G at +-8G and per dps at +-2000dps, as the factory calibration in flash describes them
+const ACCEL_PER_G: f64 = 4096.0;
+const GYRO_PER_DPS: f64 = 15335.0 / 936.0;
+// origin of the user calibration at SPI 0x8028
+const ACCEL_ORIGIN: [f64; 3] = [-66.0, 62.0, 496.0];
+const GYRO_ORIGIN: [f64; 3] = [-2.0, -2.0, 8.0];
+// the three samples of a report are 5ms apart
+const SAMPLE_INTERVAL: f64 = 0.005;
+// register file of the LSM6DS3, WHO_AM_I identifies the chip
+const REGISTER_COUNT: usize = 0x80;
+const WHO_AM_I: usize = 0x0f;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ImuConfig {
+    pub bone: u16,
+}
+
+impl Default for ImuConfig {
+    fn default() -> Self {
+        Self { bone: BONE_RIGHT_HAND }
+    }
+}
+
+pub struct Imu {
+    pub enabled: bool,
+    bone: u16,
+    gyro_range: f64,
+    accel_range: f64,
+    orientation: Option<(Quaternion, Instant)>,
+    // radians per second in the bone's frame
+    angular_velocity: (f64, f64, f64),
+    registers: [u8; REGISTER_COUNT],
+}
+
+impl Imu {
+    pub fn new(config: &ImuConfig) -> Self {
+        Self {
+            enabled: false,
+            bone: config.bone,
+            gyro_range: 2000.0,
+            accel_range: 8.0,
+            orientation: None,
+            angular_velocity: (0.0, 0.0, 0.0),
+            registers: {
+                let mut r = [0u8; REGISTER_COUNT];
+                r[WHO_AM_I] = 0x69;
+                r
+            },
+        }
+    }
+
+    // subcommand 0x42, values are kept but don't change how samples are produced
+    pub fn write_register(&mut self, addr: u8, value: u8) {
+        if let Some(r) = self.registers.get_mut(usize::from(addr)) {
+            *r = value;
+        }
+    }
+
+    // subcommand 0x43, reads past the last register return zeros
+    pub fn read_registers(&self, addr: u8, len: u8) -> Vec<u8> {
+        (usize::from(addr)..usize::from(addr) + usize::from(len))
+            .map(|i| self.registers.get(i).copied().unwrap_or(0))
+            .collect()
+    }
+
+    // subcommand 0x41
+    pub fn set_sensitivity(&mut self, gyro: u8, accel: u8) {
+        self.gyro_range = match gyro {
+            0 => 250.0,
+            1 => 500.0,
+            2 => 1000.0,
+            _ => 2000.0,
+        };
+        self.accel_range = match accel {
+            1 => 4.0,
+            2 => 2.0,
+            3 => 16.0,
+            _ => 8.0,
+        };
+    }
+
+    pub fn update(&mut self, frame: &FramePacket, now: Instant) {
+        let bones: HashMap<u16, &BoneTrans> = frame.frame.bones.iter().map(|b| (b.id, b)).collect();
+        let current = match global_rotation(&bones, self.bone) {
+            Some(q) => q,
+            None => return,
+        };
+
+        if let Some((last, at)) = self.orientation {
+            let dt = now.duration_since(at).as_secs_f64();
+            if dt > 0.0 {
+                let (x, y, z) = last.conjugate().mul(&current).rotation_vector();
+                self.angular_velocity = (x / dt, y / dt, z / dt);
+            }
+        }
+
+        self.orientation = Some((current, now));
+    }
+
+    // three samples, oldest first, as they follow the buttons in the 0x30 report
+    pub fn report(&self) -> [u8; 36] {
+        let mut buf = [0u8; 36];
+        if !self.enabled {
+            return buf;
+        }
+
+        let w = self.angular_velocity;
+        let speed = (w.0 * w.0 + w.1 * w.1 + w.2 * w.2).sqrt();
+        let gyro = to_controller(w);
+
+        for i in 0..3 {
+            let accel = match self.orientation {
+                Some((q, _)) => {
+                    let dt = (i as f64 - 2.0) * SAMPLE_INTERVAL;
+                    let q = q.mul(&Quaternion::from_axis_angle(w, speed * dt));
+                    // at rest the accelerometer reads 1G pointing up
+                    to_controller(q.conjugate().rotate((0.0, 1.0, 0.0)))
+                }
+                None => (0.0, 0.0, 1.0),
+            };
+
+            let accel_scale = ACCEL_PER_G * 8.0 / self.accel_range;
+            let gyro_scale = GYRO_PER_DPS * 2000.0 / self.gyro_range;
+            let values = [
+                ACCEL_ORIGIN[0] + accel.0 * accel_scale,
+                ACCEL_ORIGIN[1] + accel.1 * accel_scale,
+                ACCEL_ORIGIN[2] + accel.2 * accel_scale,
+                GYRO_ORIGIN[0] + gyro.0.to_degrees() * gyro_scale,
+                GYRO_ORIGIN[1] + gyro.1.to_degrees() * gyro_scale,
+                GYRO_ORIGIN[2] + gyro.2.to_degrees() * gyro_scale,
+            ];
+
+            for (j, v) in values.iter().enumerate() {
+                let raw = v.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+                let offset = i * 12 + j * 2;
+                buf[offset..offset + 2].copy_from_slice(&raw.to_le_bytes());
+            }
+        }
+
+        buf
+    }
+}
+
+fn global_rotation(bones: &HashMap<u16, &BoneTrans>, id: u16) -> Option<Quaternion> {
+    let local = Quaternion::from(&bones.get(&id)?.trans.rot);
+    match mocopi::parent(id) {
+        Some(p) => Some(global_rotation(bones, p)?.mul(&local)),
+        None => Some(local),
+    }
+}
+
+// mocopi bones are y-up with z forward, the controller is z-up with x forward and y to the left
+fn to_controller(v: (f64, f64, f64)) -> (f64, f64, f64) {
+    (v.2, -v.0, v.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use mocopi_parser::{BoneTrans, Frame, Head, Info, Position, Rotation, Transform};
+    use crate::mapping::BONE_ROOT;
+
+    // the root bone alone, it has no parent
+    fn frame(q: Quaternion) -> FramePacket {
+        let rot = Rotation { x: q.x as f32, y: q.y as f32, z: q.z as f32, w: q.w as f32 };
+        FramePacket {
+            head: Head { format: "sony motion format".to_string(), ver: 1 },
+            info: Info { addr: 0, port: 0 },
+            frame: Frame {
+                num: 0,
+                time: 0,
+                bones: vec![BoneTrans {
+                    id: BONE_ROOT,
+                    trans: Transform { rot, pos: Position { x: 0.0, y: 0.0, z: 0.0 } },
+                }],
+            },
+        }
+    }
+
+    fn imu() -> Imu {
+        let mut imu = Imu::new(&ImuConfig { bone: BONE_ROOT });
+        imu.enabled = true;
+        imu
+    }
+
+    // accelerometer x, y, z and gyro x, y, z of one sample
+    fn sample(report: &[u8; 36], i: usize) -> [i16; 6] {
+        let mut values = [0i16; 6];
+        for (j, v) in values.iter_mut().enumerate() {
+            let offset = i * 12 + j * 2;
+            *v = i16::from_le_bytes([report[offset], report[offset + 1]]);
+        }
+        values
+    }
+
+    #[test]
+    fn gravity_at_rest() {
+        let mut imu = imu();
+        imu.update(&frame(Quaternion::IDENTITY), Instant::now());
+        let report = imu.report();
+        // 1G up is the controller's z, the gyro reads its calibration origin
+        for i in 0..3 {
+            assert_eq!(sample(&report, i), [-66, 62, 496 + 4096, -2, -2, 8]);
+        }
+
+        // at +-2G a G is four times the raw value
+        imu.set_sensitivity(3, 2);
+        assert_eq!(sample(&imu.report(), 2)[..3], [-66, 62, 496 + 4 * 4096]);
+    }
+
+    #[test]
+    fn gravity_when_tilted() {
+        let mut imu = imu();
+        // rolled a quarter turn around the forward axis, the bone's x points down
+        imu.update(&frame(Quaternion::from_axis_angle((0.0, 0.0, 1.0), -std::f64::consts::FRAC_PI_2)), Instant::now());
+        let s = sample(&imu.report(), 2);
+        // so up is the bone's -x, which is the controller's y
+        assert_eq!(s[..3], [-66, 62 + 4096, 496]);
+    }
+
+    #[test]
+    fn angular_velocity() {
+        let mut imu = imu();
+        let start = Instant::now();
+        // a degree every 10ms around up is 100dps around the controller's z
+        let step = Quaternion::from_axis_angle((0.0, 1.0, 0.0), 1f64.to_radians());
+        imu.update(&frame(Quaternion::IDENTITY), start);
+        imu.update(&frame(step), start + Duration::from_millis(10));
+
+        let expected = 8.0 + 100.0 * GYRO_PER_DPS;
+        let gyro = sample(&imu.report(), 2)[3..].to_vec();
+        assert_eq!(gyro[..2], [-2, -2]);
+        assert!((f64::from(gyro[2]) - expected).abs() <= 1.0, "{:?}", gyro);
+
+        // +-250dps is eight times as fine, the accelerometer stays at +-8G
+        imu.set_sensitivity(0, 0);
+        let gyro = sample(&imu.report(), 2)[5];
+        assert!((f64::from(gyro) - (8.0 + 800.0 * GYRO_PER_DPS)).abs() <= 2.0, "{}", gyro);
+
+        // turning around up leaves gravity where it is in all three samples
+        for i in 0..3 {
+            assert_eq!(sample(&imu.report(), i)[..3], [-66, 62, 496 + 4096]);
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut imu = imu();
+        imu.update(&frame(Quaternion::IDENTITY), Instant::now());
+        imu.enabled = false;
+        assert_eq!(imu.report(), [0u8; 36]);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::{tap_amiibo, Input, InputField};
+use crate::arbiter::Arbiter;
+use crate::evdev::{self, Events, EV_KEY};
+use crate::lifecycle::{Shutdown, StopReason};
+use crate::macros::Macros;
+use crate::mcu::Mcu;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    // evdev keyboard like /dev/input/by-id/...-event-kbd, or a recording of one,
+    // without it single keys are read from the terminal
+    pub device: Option<String>,
+    pub quit: String,
+    // tap the next amiibo
+    pub amiibo: String,
+    // keys that switch an input source on and off, by source name
+    pub toggles: HashMap<String, String>,
+    // keys that start a macro by name, or cancel it while it runs
+    pub macros: HashMap<String, String>,
+    pub cancel_macros: String,
+    pub keys: Vec<KeyBinding>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct KeyBinding {
+    // KEY_* name or code
+    pub key: String,
+    pub target: InputField,
+    // how far a stick axis moves while the key is held, ignored for buttons
+    #[serde(default = "KeyBinding::full")]
+    pub value: f64,
+}
+
+impl KeyBinding {
+    fn full() -> f64 {
+        1.0
+    }
+
+    fn new(key: &str, target: InputField, value: f64) -> Self {
+        Self { key: key.to_string(), target, value }
+    }
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        use InputField::*;
+        let button = |key, target| KeyBinding::new(key, target, 1.0);
+        Self {
+            device: None,
+            quit: "KEY_ESC".to_string(),
+            amiibo: "KEY_N".to_string(),
+            toggles: HashMap::from([
+                ("KEY_F1".to_string(), "motion".to_string()),
+                ("KEY_F2".to_string(), "gamepad".to_string()),
+            ]),
+            macros: HashMap::new(),
+            cancel_macros: "KEY_F12".to_string(),
+            keys: vec![
+                button("KEY_UP", Up),
+                button("KEY_DOWN", Down),
+                button("KEY_LEFT", Left),
+                button("KEY_RIGHT", Right),
+                button("KEY_ENTER", A),
+                button("KEY_BACKSPACE", B),
+                button("KEY_X", X),
+                button("KEY_Y", Y),
+                button("KEY_Q", L),
+                button("KEY_U", R),
+                button("KEY_E", Zl),
+                button("KEY_O", Zr),
+                button("KEY_MINUS", Minus),
+                button("KEY_EQUAL", Plus),
+                button("KEY_H", Home),
+                button("KEY_C", Capture),
+                button("KEY_Z", StickLPress),
+                button("KEY_M", StickRPress),
+                KeyBinding::new("KEY_W", StickLY, 1.0),
+                KeyBinding::new("KEY_S", StickLY, -1.0),
+                KeyBinding::new("KEY_A", StickLX, -1.0),
+                KeyBinding::new("KEY_D", StickLX, 1.0),
+                KeyBinding::new("KEY_I", StickRY, 1.0),
+                KeyBinding::new("KEY_K", StickRY, -1.0),
+                KeyBinding::new("KEY_J", StickRX, -1.0),
+                KeyBinding::new("KEY_L", StickRX, 1.0),
+            ],
+        }
+    }
+}
+
+// held keys and what they are bound to
+struct KeyMap {
+    bindings: HashMap<u16, Vec<(InputField, f64)>>,
+    held: HashSet<u16>,
+}
+
+impl KeyMap {
+    fn new(keys: &[KeyBinding]) -> Result<Self, Box<dyn Error>> {
+        let mut bindings: HashMap<u16, Vec<(InputField, f64)>> = HashMap::new();
+        for k in keys {
+            bindings.entry(evdev::key_code(&k.key)?).or_default().push((k.target, k.value));
+        }
+        Ok(Self { bindings, held: HashSet::new() })
+    }
+
+    // buttons are held while any of their keys is, opposite keys on an axis cancel out
+    fn apply(&mut self, code: u16, pressed: bool, input: &mut Input) {
+        let fields = match self.bindings.get(&code) {
+            Some(f) => f.clone(),
+            None => return,
+        };
+        if pressed {
+            self.held.insert(code);
+        } else {
+            self.held.remove(&code);
+        }
+
+        for (field, _) in fields {
+            let values = self
+                .held
+                .iter()
+                .flat_map(|c| &self.bindings[c])
+                .filter(|(f, _)| *f == field)
+                .map(|(_, v)| *v);
+            let value = if field.is_axis() {
+                values.sum::<f64>().clamp(-1.0, 1.0)
+            } else if values.count() > 0 {
+                1.0
+            } else {
+                0.0
+            };
+            input.set(field, value);
+        }
+    }
+
+    fn release_all(&mut self, input: &mut Input) {
+        for code in self.held.clone() {
+            self.apply(code, false, input);
+        }
+    }
+}
+
+// key down and up from an evdev keyboard onto the keyboard's input source
+pub fn start(
+    config: &KeyboardConfig,
+    device: &str,
+    arbiter: Arbiter,
+    macros: Macros,
+    mcu: Arc<Mutex<Mcu>>,
+    amiibo: Vec<PathBuf>,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let mut map = KeyMap::new(&config.keys)?;
+    let quit = evdev::key_code(&config.quit)?;
+    let next = evdev::key_code(&config.amiibo)?;
+    let mut toggles = HashMap::new();
+    for (key, source) in &config.toggles {
+        toggles.insert(evdev::key_code(key)?, source.clone());
+    }
+    let mut macro_keys = HashMap::new();
+    for (key, name) in &config.macros {
+        macro_keys.insert(evdev::key_code(key)?, name.clone());
+    }
+    let cancel = evdev::key_code(&config.cancel_macros)?;
+    let mut events = Events::open(device)?;
+    let mut next_amiibo = 0;
+    let input = arbiter.register("keyboard");
+
+    tokio::task::spawn_blocking(move || {
+        println!("start keyboard input");
+        while !shutdown.is_stopped() {
+            let event = match events.next() {
+                Ok(Some(e)) => e,
+                Ok(None) => break,
+                Err(e) => {
+                    println!("Failed to read the keyboard: {}", e);
+                    break;
+                }
+            };
+            // value 2 is autorepeat, the key is still held
+            if event.kind != EV_KEY || event.value == 2 {
+                continue;
+            }
+            let pressed = event.value == 1;
+
+            if event.code == quit && pressed {
+                shutdown.stop(StopReason::Finished);
+                break;
+            }
+            if event.code == next && pressed && !amiibo.is_empty() {
+                tap_amiibo(&mcu, &amiibo[next_amiibo]);
+                next_amiibo = (next_amiibo + 1) % amiibo.len();
+                continue;
+            }
+            if let Some(source) = toggles.get(&event.code) {
+                if pressed {
+                    arbiter.toggle(source);
+                }
+                continue;
+            }
+            if let Some(name) = macro_keys.get(&event.code) {
+                if pressed {
+                    macros.toggle(name);
+                }
+                continue;
+            }
+            if event.code == cancel {
+                if pressed {
+                    macros.cancel_all();
+                }
+                continue;
+            }
+            map.apply(event.code, pressed, &mut input.lock().unwrap());
+        }
+
+        // a recording may end with keys down
+        map.release_all(&mut input.lock().unwrap());
+        println!("end keyboard input");
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/keys.ev");
+
+    // the left stick and the A and L buttons after every key event of the recording
+    #[test]
+    fn recorded_keys() {
+        let mut map = KeyMap::new(&KeyboardConfig::default().keys).unwrap();
+        let mut events = Events::open(FIXTURE).unwrap();
+        let mut input = Input::new();
+        let mut states = vec![];
+        while let Some(event) = events.next().unwrap() {
+            if event.kind != EV_KEY || event.value == 2 {
+                continue;
+            }
+            map.apply(event.code, event.value == 1, &mut input);
+            states.push((input.stick_l.x, input.stick_l.y, input.a, input.l));
+        }
+
+        assert_eq!(
+            states,
+            [
+                // W
+                (0.0, 1.0, false, false),
+                // W and D together
+                (1.0, 1.0, false, false),
+                // Enter and Q as a chord
+                (1.0, 1.0, true, false),
+                (1.0, 1.0, true, true),
+                // S cancels the held W, autorepeat of W changes nothing
+                (1.0, 0.0, true, true),
+                // letting go of W leaves S
+                (1.0, -1.0, true, true),
+                (1.0, -1.0, false, true),
+                // everything released
+                (1.0, 0.0, false, true),
+                (0.0, 0.0, false, true),
+                (0.0, 0.0, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn release_all() {
+        let mut map = KeyMap::new(&KeyboardConfig::default().keys).unwrap();
+        let mut input = Input::new();
+        for name in ["KEY_A", "KEY_ENTER", "KEY_Z"] {
+            map.apply(evdev::key_code(name).unwrap(), true, &mut input);
+        }
+        assert_eq!((input.stick_l.x, input.stick_l.press, input.a), (-1.0, true, true));
+        map.release_all(&mut input);
+        assert_eq!((input.stick_l.x, input.stick_l.press, input.a), (0.0, false, false));
+    }
+}
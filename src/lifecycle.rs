@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::process::Command;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    // the user quit or the session ran to its end
+    Finished,
+    Interrupted,
+    Terminated,
+    // the device or the host went away
+    Disconnected,
+    // something needed to run could not be set up
+    Failed,
+}
+
+impl StopReason {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Finished => 0,
+            Self::Failed => 1,
+            Self::Disconnected => 2,
+            // 128 + signal number like a shell would report
+            Self::Interrupted => 130,
+            Self::Terminated => 143,
+        }
+    }
+}
+
+// shared by every task, the first reason to stop wins
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<Option<StopReason>>>,
+    receiver: watch::Receiver<Option<StopReason>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(None);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn stop(&self, reason: StopReason) {
+        self.sender.send_if_modified(|r| {
+            if r.is_some() {
+                return false;
+            }
+            println!("Stopping: {:?}", reason);
+            *r = Some(reason);
+            true
+        });
+    }
+
+    pub fn reason(&self) -> Option<StopReason> {
+        *self.receiver.borrow()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    pub async fn stopped(&self) -> StopReason {
+        let mut receiver = self.receiver.clone();
+        // only fails without a sender, and that lives as long as we do
+        receiver.wait_for(|r| r.is_some()).await.map_or(StopReason::Finished, |r| r.unwrap())
+    }
+}
+
+// turn SIGINT and SIGTERM into a shutdown
+pub fn handle_signals(shutdown: Shutdown) -> Result<(), Box<dyn Error>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::task::spawn(async move {
+        let reason = tokio::select! {
+            _ = interrupt.recv() => StopReason::Interrupted,
+            _ = terminate.recv() => StopReason::Terminated,
+        };
+        shutdown.stop(reason);
+    });
+
+    Ok(())
+}
+
+// puts the terminal into single key mode and gives it back the way it was
+pub struct Terminal {
+    saved: Option<String>,
+}
+
+impl Terminal {
+    pub fn cbreak() -> Result<Self, Box<dyn Error>> {
+        let saved = Command::new("stty").args(["-F", "/dev/tty", "-g"]).output()?;
+        if !saved.status.success() {
+            return Err("no terminal to configure".into());
+        }
+
+        let status = Command::new("stty")
+            .args(["-F", "/dev/tty", "cbreak", "min", "1", "-echo"])
+            .status()?;
+        if !status.success() {
+            return Err("failed to configure the terminal".into());
+        }
+
+        Ok(Self {
+            saved: Some(String::from_utf8_lossy(&saved.stdout).trim().to_string()),
+        })
+    }
+
+    pub fn restore(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            if let Err(e) = Command::new("stty").args(["-F", "/dev/tty", &saved]).status() {
+                println!("Failed to restore the terminal: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
@@ -0,0 +1,95 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LinkConfig {
+    // reopen the device when it goes away instead of stopping
+    pub reconnect: bool,
+    pub retry_ms: u64,
+    // press HOME on the first input while the console is asleep
+    pub wake: bool,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            reconnect: true,
+            retry_ms: 1000,
+            wake: false,
+        }
+    }
+}
+
+impl LinkConfig {
+    pub fn retry(&self) -> Duration {
+        Duration::from_millis(self.retry_ms)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    // no device to talk to
+    Disconnected,
+    // device open, the host hasn't finished the USB handshake
+    Handshaking,
+    // handshake done, no input reports yet
+    Paired,
+    // input reports are sent, after 0x80 0x04
+    Streaming,
+    // the host stopped input reports with 0x80 0x05, usually because it went to sleep
+    Suspended,
+}
+
+#[derive(Clone)]
+pub struct Link {
+    state: Arc<Mutex<LinkState>>,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LinkState::Disconnected)),
+        }
+    }
+
+    pub fn get(&self) -> LinkState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn set(&self, next: LinkState) {
+        let mut state = self.state.lock().unwrap();
+        if *state != next {
+            println!("link: {:?} -> {:?}", *state, next);
+            *state = next;
+        }
+    }
+}
+
+// the gadget was unbound or the host went away, the driver returns ESHUTDOWN
+pub fn is_shutdown(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ESHUTDOWN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_state() {
+        let link = Link::new();
+        let other = link.clone();
+        assert_eq!(link.get(), LinkState::Disconnected);
+        other.set(LinkState::Handshaking);
+        assert_eq!(link.get(), LinkState::Handshaking);
+    }
+
+    #[test]
+    fn shutdown_errors() {
+        assert!(is_shutdown(&io::Error::from_raw_os_error(libc::ESHUTDOWN)));
+        assert!(!is_shutdown(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(!is_shutdown(&io::Error::from(io::ErrorKind::WouldBlock)));
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use crate::{Input, InputField};
+use crate::arbiter::Arbiter;
+
+// frames a tap holds the button, and then lets go before the next step
+const TAP_FRAMES: u64 = 3;
+// steps a macro may take without waiting, more is an endless loop
+const MAX_STEPS: usize = 10_000;
+// macros running macros
+const MAX_DEPTH: usize = 32;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct MacroConfig {
+    // by name, one step per line or separated by `;`:
+    //   press A, release A, hold ZR 2s, tap A, stick L 0.0,1.0 for 500ms, wait 3 frames,
+    //   loop 5 { ... } or loop { ... } to repeat until cancelled, run <other macro>
+    pub scripts: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+enum Step {
+    Set(InputField, f64),
+    // number of input reports
+    Wait(u64),
+    // None repeats until cancelled
+    Loop(Option<u32>, Arc<Vec<Step>>),
+    Run(String),
+}
+
+// a script into steps, durations become a number of reports `frame` apart
+fn parse(text: &str, frame: Duration) -> Result<Vec<Step>, Box<dyn Error>> {
+    // the script itself and the loops open around the current line
+    let mut blocks: Vec<(Option<u32>, Vec<Step>)> = vec![(Some(1), vec![])];
+
+    for (number, line) in text.lines().enumerate() {
+        // braces end a command too, so a loop fits on one line
+        let line = line.split('#').next().unwrap_or_default().replace('{', "{;").replace('}', ";};");
+        for command in line.split(';').map(str::trim).filter(|c| !c.is_empty()) {
+            let error = |e: Box<dyn Error>| format!("line {}: {}: {}", number + 1, command, e);
+            let words: Vec<&str> = command.split_whitespace().collect();
+
+            if command == "}" {
+                if blocks.len() == 1 {
+                    return Err(error("no loop to close".into()).into());
+                }
+                let (count, body) = blocks.pop().unwrap();
+                blocks.last_mut().unwrap().1.push(Step::Loop(count, Arc::new(body)));
+            } else if words[0].eq_ignore_ascii_case("loop") && words.last() == Some(&"{") {
+                let count = match &words[1..words.len() - 1] {
+                    [] => None,
+                    [n] => Some(n.parse().map_err(|e: std::num::ParseIntError| error(e.into()))?),
+                    _ => return Err(error("expected loop [count] {".into()).into()),
+                };
+                blocks.push((count, vec![]));
+            } else {
+                let steps = parse_command(&words, frame).map_err(error)?;
+                blocks.last_mut().unwrap().1.extend(steps);
+            }
+        }
+    }
+
+    if blocks.len() > 1 {
+        return Err("a loop is never closed".into());
+    }
+    Ok(blocks.pop().unwrap().1)
+}
+
+fn parse_command(words: &[&str], frame: Duration) -> Result<Vec<Step>, Box<dyn Error>> {
+    let command = words[0].to_ascii_lowercase();
+    let steps = match (command.as_str(), &words[1..]) {
+        ("press", [b]) => vec![Step::Set(button(b)?, 1.0)],
+        ("release", [b]) => vec![Step::Set(button(b)?, 0.0)],
+        ("hold", [b, duration @ ..]) => {
+            let b = button(b)?;
+            vec![Step::Set(b, 1.0), Step::Wait(frames(duration, frame)?), Step::Set(b, 0.0)]
+        }
+        ("tap", [b]) => {
+            let b = button(b)?;
+            vec![Step::Set(b, 1.0), Step::Wait(TAP_FRAMES), Step::Set(b, 0.0), Step::Wait(TAP_FRAMES)]
+        }
+        // stays where it is put unless it is `for` a while
+        ("stick", [side, rest @ ..]) => {
+            let (x_field, y_field) = match side.to_ascii_lowercase().as_str() {
+                "l" | "left" => (InputField::StickLX, InputField::StickLY),
+                "r" | "right" => (InputField::StickRX, InputField::StickRY),
+                _ => return Err(format!("unknown stick {}", side).into()),
+            };
+            let end = rest.iter().position(|w| w.eq_ignore_ascii_case("for")).unwrap_or(rest.len());
+            let coords = rest[..end].concat();
+            let (x, y) = coords.split_once(',').ok_or("expected x,y")?;
+            let (x, y): (f64, f64) = (x.parse()?, y.parse()?);
+            let mut steps = vec![Step::Set(x_field, x.clamp(-1.0, 1.0)), Step::Set(y_field, y.clamp(-1.0, 1.0))];
+            if end < rest.len() {
+                steps.push(Step::Wait(frames(&rest[end + 1..], frame)?));
+                steps.extend([Step::Set(x_field, 0.0), Step::Set(y_field, 0.0)]);
+            }
+            steps
+        }
+        ("wait", duration) => vec![Step::Wait(frames(duration, frame)?)],
+        ("run", [name]) => vec![Step::Run(name.to_string())],
+        _ => return Err("unknown command".into()),
+    };
+    Ok(steps)
+}
+
+// names of the input fields, in any case
+fn button(name: &str) -> Result<InputField, Box<dyn Error>> {
+    let field = InputField::deserialize(name.to_ascii_lowercase().into_deserializer())
+        .map_err(|e: serde::de::value::Error| e.to_string())?;
+    if field.is_axis() {
+        return Err(format!("{} is a stick axis, use stick", name).into());
+    }
+    Ok(field)
+}
+
+// `3 frames`, `500ms` or `2s` as a number of reports, rounded up
+fn frames(words: &[&str], frame: Duration) -> Result<u64, Box<dyn Error>> {
+    let text = words.concat().to_ascii_lowercase();
+    if let Some(n) = text.strip_suffix("frames").or_else(|| text.strip_suffix("frame")) {
+        return Ok(n.parse()?);
+    }
+    let duration = if let Some(ms) = text.strip_suffix("ms") {
+        Duration::from_secs_f64(ms.parse::<f64>()? / 1000.0)
+    } else if let Some(s) = text.strip_suffix('s') {
+        Duration::from_secs_f64(s.parse()?)
+    } else {
+        return Err(format!("expected a duration like 3 frames, 500ms or 2s, got {:?}", text).into());
+    };
+    Ok((duration.as_secs_f64() / frame.as_secs_f64()).ceil() as u64)
+}
+
+struct Block {
+    steps: Arc<Vec<Step>>,
+    next: usize,
+    // passes left including this one, None for ever
+    passes: Option<u32>,
+}
+
+struct Running {
+    name: String,
+    // the script, then the loops and macros it is in
+    stack: Vec<Block>,
+    wait: u64,
+    input: Input,
+}
+
+impl Running {
+    // one report further, false once the macro is done
+    fn tick(&mut self, scripts: &HashMap<String, Arc<Vec<Step>>>) -> Result<bool, String> {
+        for _ in 0..MAX_STEPS {
+            if self.wait > 0 {
+                self.wait -= 1;
+                return Ok(true);
+            }
+            let block = match self.stack.last_mut() {
+                Some(b) => b,
+                None => return Ok(false),
+            };
+
+            if block.next == block.steps.len() {
+                match &mut block.passes {
+                    Some(n) if *n <= 1 => {
+                        self.stack.pop();
+                    }
+                    Some(n) => {
+                        *n -= 1;
+                        block.next = 0;
+                    }
+                    None => block.next = 0,
+                }
+                continue;
+            }
+
+            let steps = Arc::clone(&block.steps);
+            block.next += 1;
+            match &steps[block.next - 1] {
+                Step::Set(field, value) => self.input.set(*field, *value),
+                Step::Wait(n) => self.wait = *n,
+                Step::Loop(Some(0), _) => {}
+                Step::Loop(passes, body) => self.push(Arc::clone(body), *passes)?,
+                Step::Run(name) => {
+                    let body = scripts.get(name).ok_or_else(|| format!("no macro {}", name))?;
+                    self.push(Arc::clone(body), Some(1))?;
+                }
+            }
+        }
+        Err("it never waits".to_string())
+    }
+
+    fn push(&mut self, steps: Arc<Vec<Step>>, passes: Option<u32>) -> Result<(), String> {
+        if self.stack.len() >= MAX_DEPTH {
+            return Err("macros are nested too deep".to_string());
+        }
+        self.stack.push(Block { steps, next: 0, passes });
+        Ok(())
+    }
+}
+
+// the macros from the config, stepped once for every input report
+#[derive(Clone)]
+pub struct Macros {
+    scripts: Arc<HashMap<String, Arc<Vec<Step>>>>,
+    running: Arc<Mutex<Vec<Running>>>,
+    input: Arc<Mutex<Input>>,
+    arbiter: Arbiter,
+}
+
+impl Macros {
+    pub fn new(config: &MacroConfig, arbiter: &Arbiter, frame: Duration) -> Result<Self, Box<dyn Error>> {
+        let mut scripts = HashMap::new();
+        for (name, text) in &config.scripts {
+            let steps = parse(text, frame).map_err(|e| format!("macro {}: {}", name, e))?;
+            scripts.insert(name.clone(), Arc::new(steps));
+        }
+        Ok(Self {
+            scripts: Arc::new(scripts),
+            running: Arc::new(Mutex::new(vec![])),
+            input: arbiter.register("macro"),
+            arbiter: arbiter.clone(),
+        })
+    }
+
+    // from the beginning, unless it is running already
+    pub fn start(&self, name: &str) {
+        let steps = match self.scripts.get(name) {
+            Some(s) => Arc::clone(s),
+            None => {
+                println!("No macro {}", name);
+                return;
+            }
+        };
+        let mut running = self.running.lock().unwrap();
+        if running.iter().any(|r| r.name == name) {
+            return;
+        }
+        println!("Macro {} started", name);
+        running.push(Running {
+            name: name.to_string(),
+            stack: vec![Block { steps, next: 0, passes: Some(1) }],
+            wait: 0,
+            input: Input::new(),
+        });
+    }
+
+    // start a macro, or cancel it when it is running
+    pub fn toggle(&self, name: &str) {
+        let mut running = self.running.lock().unwrap();
+        if let Some(i) = running.iter().position(|r| r.name == name) {
+            running.remove(i);
+            println!("Macro {} cancelled", name);
+            return;
+        }
+        drop(running);
+        self.start(name);
+    }
+
+    pub fn cancel_all(&self) {
+        for r in self.running.lock().unwrap().drain(..) {
+            println!("Macro {} cancelled", r.name);
+        }
+    }
+
+    // advance every running macro by one report, what they hold goes to the macro input source
+    pub fn tick(&self) {
+        let mut running = self.running.lock().unwrap();
+        // what a macro set on its last step still goes out in this report
+        let mut finished = vec![];
+        running.retain_mut(|r| match r.tick(&self.scripts) {
+            Ok(true) => true,
+            Ok(false) => {
+                println!("Macro {} finished", r.name);
+                finished.push(r.input.clone());
+                false
+            }
+            Err(e) => {
+                println!("Macro {} stopped: {}", r.name, e);
+                false
+            }
+        });
+
+        // buttons of all of them, sticks of the last started one that moves them
+        let mut input = Input::new();
+        for theirs in running.iter().map(|r| &r.input).chain(&finished) {
+            input.or_buttons(theirs);
+            for (stick, theirs) in [(&mut input.stick_l, &theirs.stick_l), (&mut input.stick_r, &theirs.stick_r)] {
+                if theirs.magnitude() > 0.0 {
+                    stick.x = theirs.x;
+                    stick.y = theirs.y;
+                }
+            }
+        }
+        *self.input.lock().unwrap() = input;
+        self.arbiter.set_active("macro", !running.is_empty() || !finished.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbiter::ArbiterConfig;
+
+    const FRAME: Duration = Duration::from_millis(8);
+
+    fn macros(scripts: &[(&str, &str)]) -> (Macros, Arbiter) {
+        let config = MacroConfig { scripts: scripts.iter().map(|(n, s)| (n.to_string(), s.to_string())).collect() };
+        let arbiter = Arbiter::new(&ArbiterConfig::default());
+        (Macros::new(&config, &arbiter, FRAME).unwrap(), arbiter)
+    }
+
+    // A in every report until the macros are done and a few after
+    fn run(macros: &Macros, arbiter: &Arbiter, reports: usize) -> Vec<bool> {
+        (0..reports)
+            .map(|_| {
+                macros.tick();
+                arbiter.combine().get(InputField::A) != 0.0
+            })
+            .collect()
+    }
+
+    fn running(macros: &Macros) -> usize {
+        macros.running.lock().unwrap().len()
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(frames(&["3", "frames"], FRAME).unwrap(), 3);
+        assert_eq!(frames(&["1", "frame"], FRAME).unwrap(), 1);
+        // 62.5 reports
+        assert_eq!(frames(&["500ms"], FRAME).unwrap(), 63);
+        assert_eq!(frames(&["500", "ms"], FRAME).unwrap(), 63);
+        assert_eq!(frames(&["2s"], FRAME).unwrap(), 250);
+        assert_eq!(frames(&["8ms"], FRAME).unwrap(), 1);
+        assert!(frames(&["3"], FRAME).is_err());
+        assert!(frames(&["soon"], FRAME).is_err());
+    }
+
+    #[test]
+    fn one_line_loop() {
+        let steps = parse("loop 2 { press a; wait 1 frame; release a }", FRAME).unwrap();
+        match steps.as_slice() {
+            [Step::Loop(Some(2), body)] => assert!(matches!(
+                body.as_slice(),
+                [Step::Set(InputField::A, v), Step::Wait(1), Step::Set(InputField::A, r)] if *v == 1.0 && *r == 0.0
+            )),
+            s => panic!("{:?}", s),
+        }
+        assert!(matches!(parse("loop { tap b }", FRAME).unwrap().as_slice(), [Step::Loop(None, _)]));
+    }
+
+    #[test]
+    fn nested_loops() {
+        let steps = parse("loop 2 {\n  loop 3 { tap a }\n  wait 5 frames\n}", FRAME).unwrap();
+        match steps.as_slice() {
+            [Step::Loop(Some(2), outer)] => match outer.as_slice() {
+                [Step::Loop(Some(3), inner), Step::Wait(5)] => assert_eq!(inner.len(), 4),
+                s => panic!("{:?}", s),
+            },
+            s => panic!("{:?}", s),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text| parse(text, FRAME).unwrap_err().to_string();
+        assert_eq!(error("loop 2 {\ntap a"), "a loop is never closed");
+        assert!(error("tap a\n}").starts_with("line 2: }: no loop to close"));
+        assert!(error("tap q").starts_with("line 1: tap q:"));
+        assert!(error("press stick_l_x").contains("use stick"));
+        assert!(error("loop 2 3 { tap a }").contains("expected loop [count] {"));
+        assert!(error("jump").contains("unknown command"));
+    }
+
+    #[test]
+    fn stepping() {
+        let (m, arbiter) = macros(&[("a", "loop 2 { tap a }"), ("outer", "run a\nwait 2 frames\nrun a")]);
+        m.start("a");
+        let a = [true, true, true, false, false, false];
+        assert_eq!(run(&m, &arbiter, 13), [&a[..], &a[..], &[false]].concat());
+        assert_eq!(running(&m), 0);
+
+        m.start("outer");
+        let reports = run(&m, &arbiter, 27);
+        assert_eq!(reports.iter().filter(|a| **a).count(), 12);
+        assert_eq!(reports[12..14], [false, false]);
+        assert_eq!(reports[14..17], [true, true, true]);
+        assert_eq!(running(&m), 0);
+    }
+
+    #[test]
+    fn hold_for_a_duration() {
+        // 45ms at 8ms a report is 6 reports
+        let (m, arbiter) = macros(&[("hold", "hold a 45ms")]);
+        m.start("hold");
+        assert_eq!(run(&m, &arbiter, 8), [true, true, true, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn last_step_reaches_a_report() {
+        let (m, arbiter) = macros(&[("late", "wait 2 frames; press a")]);
+        m.start("late");
+        assert_eq!(run(&m, &arbiter, 4), [false, false, true, false]);
+        assert_eq!(running(&m), 0);
+    }
+
+    #[test]
+    fn endless_steps_are_stopped() {
+        let (m, arbiter) = macros(&[("spin", "loop { press a; release a }")]);
+        let mut r = Running { name: "spin".to_string(), stack: vec![], wait: 0, input: Input::new() };
+        r.push(Arc::clone(&m.scripts["spin"]), Some(1)).unwrap();
+        assert_eq!(r.tick(&m.scripts).unwrap_err(), "it never waits");
+
+        m.start("spin");
+        run(&m, &arbiter, 1);
+        assert_eq!(running(&m), 0);
+    }
+
+    #[test]
+    fn deep_nesting_is_stopped() {
+        let (m, _) = macros(&[("again", "run again")]);
+        let mut r = Running { name: "again".to_string(), stack: vec![], wait: 0, input: Input::new() };
+        r.push(Arc::clone(&m.scripts["again"]), Some(1)).unwrap();
+        assert_eq!(r.tick(&m.scripts).unwrap_err(), "macros are nested too deep");
+        assert_eq!(r.stack.len(), MAX_DEPTH);
+    }
+
+    #[test]
+    fn toggle_and_cancel() {
+        let (m, arbiter) = macros(&[("mash", "loop { tap a }")]);
+        m.toggle("mash");
+        assert_eq!(run(&m, &arbiter, 2), [true, true]);
+        m.toggle("mash");
+        assert_eq!(run(&m, &arbiter, 1), [false]);
+        m.start("mash");
+        m.start("mash");
+        assert_eq!(running(&m), 1);
+        m.cancel_all();
+        assert_eq!(run(&m, &arbiter, 1), [false]);
+    }
+}
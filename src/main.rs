@@ -1,111 +1,131 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::cell::{Ref, RefCell};
-use std::collections::HashMap;
-use csv::WriterBuilder;
-use local_ip_address::local_ip;
-use mocopi_parser::SkeletonOrFrame;
-use serde::Serialize;
-use std::{env, thread};
+mod arbiter;
+mod calibration;
+mod capture;
+mod config;
+mod evdev;
+mod gamepad;
+mod feedback;
+mod identity;
+mod imu;
+mod keyboard;
+mod lifecycle;
+mod link;
+mod macros;
+mod mapping;
+mod mcu;
+mod mocopi;
+mod pacing;
+mod pcap;
+mod protocol;
+mod quaternion;
+mod rumble;
+mod simulator;
+mod spi;
+mod tas;
+
+use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Cursor, Read, stdin, Write};
-use std::net::UdpSocket;
-use std::path::Path;
-use std::process::Command;
-use std::sync::{Arc, mpsc, Mutex, MutexGuard};
-use std::thread::sleep;
+use std::path::{Path, PathBuf};
+use std::io::{self, ErrorKind, Read, stdin, Write};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use bitvec::{bitarr, bits};
-use bitvec::macros::internal::funty::Fundamental;
-use bitvec::prelude::{Lsb0, Msb0};
-use bitvec::slice::BitSlice;
-use bitvec::view::BitView;
-use crossbeam_channel::unbounded;
-
-#[derive(Serialize)]
-struct Row<'a> {
-    id: &'a str,
-    time: &'a str,
-    rot_x: f32,
-    rot_y: f32,
-    rot_z: f32,
-    rot_w: f32,
-    pos_x: f32,
-    pos_y: f32,
-    pos_z: f32,
-}
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use csv::WriterBuilder;
+use local_ip_address::local_ip;
+use serde::Deserialize;
+use crate::arbiter::Arbiter;
+use crate::calibration::Calibration;
+use crate::capture::{Recorded, Recorder};
+use crate::config::Config;
+use crate::feedback::ControllerFeedback;
+use crate::identity::{DeviceType, Identity, Orientation};
+use crate::imu::Imu;
+use crate::lifecycle::{Shutdown, StopReason, Terminal};
+use crate::link::{Link, LinkConfig, LinkState};
+use crate::macros::Macros;
+use crate::mcu::{Amiibo, Mcu};
+use crate::pacing::{Clock, Jitter, Pacer, PacingConfig};
+use crate::protocol::{InputReport, OutputReport, Reply, ReportMode, Subcommand, UsbCommand, IMU_SIZE};
+use crate::rumble::RumbleEvents;
+use crate::spi::Flash;
+use crate::tas::Tas;
 
-lazy_static! {
-    static ref SPI_ROM_DATA: HashMap<u8, Vec<u8>> = {
-        HashMap::from([
-            (
-                0x60,
-                vec![
-                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                    0xff, 0xff, 0x03, 0xa0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0xff, 0xff, 0xff, 0xff,
-                    0xf0, 0xff, 0x89, 0x00, 0xf0, 0x01, 0x00, 0x40, 0x00, 0x40, 0x00, 0x40, 0xf9, 0xff, 0x06, 0x00,
-                    0x09, 0x00, 0xe7, 0x3b, 0xe7, 0x3b, 0xe7, 0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xba, 0x15, 0x62,
-                    0x11, 0xb8, 0x7f, 0x29, 0x06, 0x5b, 0xff, 0xe7, 0x7e, 0x0e, 0x36, 0x56, 0x9e, 0x85, 0x60, 0xff,
-                    0x32, 0x32, 0x32, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                    0x50, 0xfd, 0x00, 0x00, 0xc6, 0x0f, 0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14, 0x54, 0x41,
-                    0x15, 0x54, 0xc7, 0x79, 0x9c, 0x33, 0x36, 0x63, 0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14,
-                    0x54, 0x41, 0x15, 0x54, 0xc7, 0x79, 0x9c, 0x33, 0x36, 0x63, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                ],
-            ),
-            (
-                0x80,
-                vec![
-                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
-                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xb2, 0xa1, 0xbe, 0xff, 0x3e, 0x00, 0xf0, 0x01, 0x00, 0x40,
-                    0x00, 0x40, 0x00, 0x40, 0xfe, 0xff, 0xfe, 0xff, 0x08, 0x00, 0xe7, 0x3b, 0xe7, 0x3b, 0xe7, 0x3b,
-                ],
-            )
-        ])
-    };
-}
+// input reports the simulated console waits for after the handshake
+const SIMULATED_REPORTS: usize = 100;
+const JITTER_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// how long HOME is held to wake the console
+const WAKE_PRESS: Duration = Duration::from_millis(100);
+// reported for subcommand 0x50, a full battery
+const BATTERY_VOLTAGE: u16 = 0x0683;
+// how far a Joy-Con stick has to be pushed to show on the hat of the 0x3f report
+const SIMPLE_HAT_THRESHOLD: f64 = 0.5;
+// how long an amiibo stays on the reader
+const AMIIBO_TAP: Duration = Duration::from_secs(3);
 
-fn write(
-    writable: &mut dyn Write,
-    ack: u8,
-    cmd: u8,
-    buf: &[u8],
-) -> Result<(), Box<dyn Error>> {
-    let mut data = vec![ack, cmd];
-    data.extend(buf);
-    data.append(&mut vec![0u8; 62 - buf.len()]);
-    writable.write(&data)?;
+fn send(writable: &mut dyn Write, report: &InputReport) -> Result<(), Box<dyn Error>> {
+    let data = report.encode()?;
+    writable.write_all(&data)?;
 
     println!("Write: {:02X?}", data);
 
     Ok(())
 }
 
-fn uart(
-    writable: &mut dyn Write,
-    input: &Input,
-    count: u8,
-    ack: bool,
-    sub_cmd: u8,
-    data: &[u8],
+fn reply(
+    writer: &Sender<InputReport>,
+    state: &ControllerState,
+    timer: u8,
+    reply: Reply,
 ) -> Result<(), Box<dyn Error>> {
-    let ack_byte = if ack {
-        if data.len() > 0 { 0x80 | sub_cmd } else { 0x00 }
-    } else {
-        0x00
-    };
+    writer.send(InputReport::Reply { timer, input: state.input_buf(), reply })?;
+    Ok(())
+}
 
-    let mut buf = input.get_buf().to_vec();
-    buf.append(&mut vec![ack_byte, sub_cmd]);
-    buf.append(&mut data.to_vec());
+// the only place reports are written, replies and input reports queue up here
+fn start_writer<W: Write + Send + 'static>(
+    device: Arc<Mutex<Option<W>>>,
+    link: Link,
+    done: Receiver<()>,
+) -> (Sender<InputReport>, tokio::task::JoinHandle<()>) {
+    let (sender, receiver) = unbounded::<InputReport>();
 
-    write(writable, 0x21, count, buf.as_slice())?;
+    let write = move |report: &InputReport| {
+        // reports queued while the device is gone are dropped
+        if let Some(w) = device.lock().unwrap().as_mut() {
+            if let Err(e) = send(w, report) {
+                // the reader runs into the same error and reopens the device
+                if link.get() != LinkState::Disconnected {
+                    println!("Failed to write: {}", e);
+                    link.set(LinkState::Disconnected);
+                }
+            }
+        }
+    };
 
-    Ok(())
+    let task = tokio::task::spawn_blocking(move || {
+        loop {
+            select! {
+                recv(receiver) -> r => match r {
+                    Ok(r) => write(&r),
+                    Err(_) => break,
+                },
+                // write what is already queued, then stop
+                recv(done) -> _ => {
+                    for r in receiver.try_iter() {
+                        write(&r);
+                    }
+                    break;
+                }
+            }
+        }
+        println!("end writing");
+    });
+
+    (sender, task)
 }
 
 // fn get_input_buffer(input: &Input) -> [u8; 11] {
@@ -243,12 +263,20 @@ pub struct Button {
     pub capture: u8,
 }
 
+#[derive(Clone)]
 pub struct Stick {
     pub x: f64,
     pub y: f64,
     pub press: bool,
 }
 
+impl Stick {
+    // how far the stick is pushed, 0 at the center
+    pub fn magnitude(&self) -> f64 {
+        self.x.hypot(self.y)
+    }
+}
+
 pub struct ControllerInput {
     pub dpad: Dpad,
     pub button: Button,
@@ -266,6 +294,42 @@ pub struct Controller {
     pub input: ControllerInput,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputField {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    X,
+    Y,
+    L,
+    R,
+    Zl,
+    Zr,
+    Minus,
+    Plus,
+    Home,
+    Capture,
+    Sl,
+    Sr,
+    StickLPress,
+    StickRPress,
+    StickLX,
+    StickLY,
+    StickRX,
+    StickRY,
+}
+
+impl InputField {
+    pub fn is_axis(&self) -> bool {
+        matches!(self, Self::StickLX | Self::StickLY | Self::StickRX | Self::StickRY)
+    }
+}
+
+#[derive(Clone)]
 struct Input {
     pub up: bool,
     pub down: bool,
@@ -286,6 +350,11 @@ struct Input {
     pub plus: bool,
     pub home: bool,
     pub capture: bool,
+
+    // rail buttons of a single Joy-Con
+    pub sl: bool,
+    pub sr: bool,
+
     pub stick_l: Stick,
     pub stick_r: Stick,
 }
@@ -309,6 +378,8 @@ impl Input {
             plus: false,
             home: false,
             capture: false,
+            sl: false,
+            sr: false,
             stick_l: Stick {
                 x: 0.0,
                 y: 0.0,
@@ -322,8 +393,151 @@ impl Input {
         }
     }
 
-    pub fn get_buf(&self) -> [u8; 11] {
-        let left =
+    // buttons are pressed for any non-zero value, stick axes take the value as is
+    pub fn set(&mut self, field: InputField, value: f64) {
+        let pressed = value != 0.0;
+        match field {
+            InputField::Up => self.up = pressed,
+            InputField::Down => self.down = pressed,
+            InputField::Left => self.left = pressed,
+            InputField::Right => self.right = pressed,
+            InputField::A => self.a = pressed,
+            InputField::B => self.b = pressed,
+            InputField::X => self.x = pressed,
+            InputField::Y => self.y = pressed,
+            InputField::L => self.l = pressed,
+            InputField::R => self.r = pressed,
+            InputField::Zl => self.zl = pressed,
+            InputField::Zr => self.zr = pressed,
+            InputField::Minus => self.minus = pressed,
+            InputField::Plus => self.plus = pressed,
+            InputField::Home => self.home = pressed,
+            InputField::Capture => self.capture = pressed,
+            InputField::Sl => self.sl = pressed,
+            InputField::Sr => self.sr = pressed,
+            InputField::StickLPress => self.stick_l.press = pressed,
+            InputField::StickRPress => self.stick_r.press = pressed,
+            InputField::StickLX => self.stick_l.x = value,
+            InputField::StickLY => self.stick_l.y = value,
+            InputField::StickRX => self.stick_r.x = value,
+            InputField::StickRY => self.stick_r.y = value,
+        }
+    }
+
+    // 1 for pressed buttons, stick axes as they are
+    pub fn get(&self, field: InputField) -> f64 {
+        let pressed = match field {
+            InputField::Up => self.up,
+            InputField::Down => self.down,
+            InputField::Left => self.left,
+            InputField::Right => self.right,
+            InputField::A => self.a,
+            InputField::B => self.b,
+            InputField::X => self.x,
+            InputField::Y => self.y,
+            InputField::L => self.l,
+            InputField::R => self.r,
+            InputField::Zl => self.zl,
+            InputField::Zr => self.zr,
+            InputField::Minus => self.minus,
+            InputField::Plus => self.plus,
+            InputField::Home => self.home,
+            InputField::Capture => self.capture,
+            InputField::Sl => self.sl,
+            InputField::Sr => self.sr,
+            InputField::StickLPress => self.stick_l.press,
+            InputField::StickRPress => self.stick_r.press,
+            InputField::StickLX => return self.stick_l.x,
+            InputField::StickLY => return self.stick_l.y,
+            InputField::StickRX => return self.stick_r.x,
+            InputField::StickRY => return self.stick_r.y,
+        };
+        f64::from(u8::from(pressed))
+    }
+
+    // hold every button the other input holds too
+    pub fn or_buttons(&mut self, other: &Input) {
+        self.up |= other.up;
+        self.down |= other.down;
+        self.left |= other.left;
+        self.right |= other.right;
+        self.a |= other.a;
+        self.b |= other.b;
+        self.x |= other.x;
+        self.y |= other.y;
+        self.l |= other.l;
+        self.r |= other.r;
+        self.zl |= other.zl;
+        self.zr |= other.zr;
+        self.minus |= other.minus;
+        self.plus |= other.plus;
+        self.home |= other.home;
+        self.capture |= other.capture;
+        self.sl |= other.sl;
+        self.sr |= other.sr;
+        self.stick_l.press |= other.stick_l.press;
+        self.stick_r.press |= other.stick_r.press;
+    }
+
+    // nothing held and both sticks centered
+    pub fn is_neutral(&self) -> bool {
+        let buttons = [
+            self.up, self.down, self.left, self.right, self.a, self.b, self.x, self.y, self.l, self.r, self.zl,
+            self.zr, self.minus, self.plus, self.home, self.capture, self.sl, self.sr, self.stick_l.press,
+            self.stick_r.press,
+        ];
+        !buttons.contains(&true) && self.stick_l.magnitude() == 0.0 && self.stick_r.magnitude() == 0.0
+    }
+
+    // translate the input as the player means it into what the device physically reports
+    pub fn for_device(&self, device: DeviceType, orientation: Orientation) -> Self {
+        let mut raw = self.clone();
+        if orientation == Orientation::Vertical {
+            return raw;
+        }
+
+        match device {
+            DeviceType::Pro => {}
+            // held with the stick on the left, the rail faces up
+            DeviceType::Left => {
+                raw.up = self.y;
+                raw.right = self.x;
+                raw.down = self.a;
+                raw.left = self.b;
+                raw.sl = self.l || self.zl;
+                raw.sr = self.r || self.zr;
+                raw.l = false;
+                raw.zl = false;
+                raw.minus = self.minus || self.plus;
+                raw.capture = self.capture || self.home;
+                raw.stick_l.x = self.stick_l.y;
+                raw.stick_l.y = -self.stick_l.x;
+            }
+            // held with the stick on the left, the rail faces up
+            DeviceType::Right => {
+                raw.x = self.a;
+                raw.a = self.b;
+                raw.b = self.y;
+                raw.y = self.x;
+                raw.sl = self.l || self.zl;
+                raw.sr = self.r || self.zr;
+                raw.r = false;
+                raw.zr = false;
+                raw.plus = self.plus || self.minus;
+                raw.stick_r.press = self.stick_l.press;
+                raw.stick_r.x = -self.stick_l.y;
+                raw.stick_r.y = self.stick_l.x;
+            }
+        }
+
+        raw
+    }
+
+    pub fn get_buf(&self, device: DeviceType, calibration: &Calibration) -> [u8; 11] {
+        let has_left = device != DeviceType::Right;
+        let has_right = device != DeviceType::Left;
+
+        let mut left =
             Self::bit_input(self.y, 0) |
                 Self::bit_input(self.x, 1) |
                 Self::bit_input(self.b, 2) |
@@ -331,15 +545,15 @@ impl Input {
                 Self::bit_input(self.r, 6) |
                 Self::bit_input(self.zr, 7);
 
-        let center =
+        let mut center =
             Self::bit_input(self.minus, 0) |
                 Self::bit_input(self.plus, 1) |
-                Self::bit_input(self.stick_l.press, 2) |
-                Self::bit_input(self.stick_r.press, 3) |
+                Self::bit_input(self.stick_r.press, 2) |
+                Self::bit_input(self.stick_l.press, 3) |
                 Self::bit_input(self.home, 4) |
                 Self::bit_input(self.capture, 5);
 
-        let right =
+        let mut right =
             Self::bit_input(self.down, 0) |
                 Self::bit_input(self.up, 1) |
                 Self::bit_input(self.right, 2) |
@@ -347,18 +561,30 @@ impl Input {
                 Self::bit_input(self.l, 6) |
                 Self::bit_input(self.zl, 7);
 
-        let lx = ((1.0 + self.stick_l.x) * 2047.5).round() as u16;
-        let ly = ((1.0 + self.stick_l.y) * 2047.5).round() as u16;
-        let rx = ((1.0 + self.stick_r.x) * 2047.5).round() as u16;
-        let ry = ((1.0 + self.stick_r.y) * 2047.5).round() as u16;
+        let rail = Self::bit_input(self.sr, 4) | Self::bit_input(self.sl, 5);
+        match device {
+            DeviceType::Pro => {}
+            DeviceType::Left => {
+                left = 0;
+                center &= 0b0010_1001;
+                right |= rail;
+            }
+            DeviceType::Right => {
+                left |= rail;
+                center &= 0b0001_0110;
+                right = 0;
+            }
+        }
 
-        println!("lx: {}, ly: {}, rx: {}, ry: {}", lx, ly, rx, ry);
+        // the console undoes the calibration it read from flash, so full deflection is its maximum
+        let (lx, ly) = calibration.left.encode(self.stick_l.x, self.stick_l.y);
+        let (rx, ry) = calibration.right.encode(self.stick_r.x, self.stick_r.y);
 
-        let left_stick = Self::pack_shorts(lx, ly);
-        let right_stick = Self::pack_shorts(rx, ry);
+        let left_stick = if has_left { Self::pack_shorts(lx, ly) } else { [0; 3] };
+        let right_stick = if has_right { Self::pack_shorts(rx, ry) } else { [0; 3] };
 
         [
-            0x81,
+            device.connection_info(),
             left,
             center,
             right,
@@ -372,8 +598,90 @@ impl Input {
         ]
     }
 
+    // buttons, hat and sticks of the 0x3f report, sticks are 0x8000 at rest and grow down and to the right
+    pub fn get_simple(&self, device: DeviceType) -> ([u8; 2], u8, [u16; 4]) {
+        let center =
+            Self::bit_input(self.minus, 0) |
+                Self::bit_input(self.plus, 1) |
+                Self::bit_input(self.stick_l.press, 2) |
+                Self::bit_input(self.stick_r.press, 3) |
+                Self::bit_input(self.home, 4) |
+                Self::bit_input(self.capture, 5);
+
+        match device {
+            DeviceType::Pro => {
+                let face =
+                    Self::bit_input(self.b, 0) |
+                        Self::bit_input(self.a, 1) |
+                        Self::bit_input(self.y, 2) |
+                        Self::bit_input(self.x, 3) |
+                        Self::bit_input(self.l, 4) |
+                        Self::bit_input(self.r, 5) |
+                        Self::bit_input(self.zl, 6) |
+                        Self::bit_input(self.zr, 7);
+                let sticks = [
+                    Self::simple_axis(self.stick_l.x),
+                    Self::simple_axis(-self.stick_l.y),
+                    Self::simple_axis(self.stick_r.x),
+                    Self::simple_axis(-self.stick_r.y),
+                ];
+                ([face, center], Self::hat(self.up, self.right, self.down, self.left), sticks)
+            }
+            // a single Joy-Con reports its stick as the hat
+            DeviceType::Left | DeviceType::Right => {
+                let (face, stick) = if device == DeviceType::Left {
+                    let face =
+                        Self::bit_input(self.left, 0) |
+                            Self::bit_input(self.down, 1) |
+                            Self::bit_input(self.up, 2) |
+                            Self::bit_input(self.right, 3);
+                    (face, &self.stick_l)
+                } else {
+                    let face =
+                        Self::bit_input(self.a, 0) |
+                            Self::bit_input(self.x, 1) |
+                            Self::bit_input(self.b, 2) |
+                            Self::bit_input(self.y, 3);
+                    (face, &self.stick_r)
+                };
+                let face = face | Self::bit_input(self.sl, 4) | Self::bit_input(self.sr, 5);
+                let center = center |
+                    Self::bit_input(self.l || self.r, 6) |
+                    Self::bit_input(self.zl || self.zr, 7);
+                let hat = Self::hat(
+                    stick.y > SIMPLE_HAT_THRESHOLD,
+                    stick.x > SIMPLE_HAT_THRESHOLD,
+                    stick.y < -SIMPLE_HAT_THRESHOLD,
+                    stick.x < -SIMPLE_HAT_THRESHOLD,
+                );
+                ([face, center], hat, [0x8000; 4])
+            }
+        }
+    }
+
+    // 0 is up, counting clockwise in eighths, 8 when nothing is held
+    fn hat(up: bool, right: bool, down: bool, left: bool) -> u8 {
+        let vertical = up as i8 - down as i8;
+        let horizontal = right as i8 - left as i8;
+        match (vertical, horizontal) {
+            (1, 0) => 0,
+            (1, 1) => 1,
+            (0, 1) => 2,
+            (-1, 1) => 3,
+            (-1, 0) => 4,
+            (-1, -1) => 5,
+            (0, -1) => 6,
+            (1, -1) => 7,
+            _ => 8,
+        }
+    }
+
+    fn simple_axis(v: f64) -> u16 {
+        ((1.0 + v.clamp(-1.0, 1.0)) * 32767.5).round() as u16
+    }
+
     fn bit_input(input: bool, offset: u32) -> u8 {
-        if input { (1 as u8).checked_shl(offset).unwrap_or(0) } else { 0 }
+        if input { 1u8.checked_shl(offset).unwrap_or(0) } else { 0 }
     }
 
     fn pack_shorts(v1: u16, v2: u16) -> [u8; 3] {
@@ -474,260 +782,556 @@ impl Input {
 //     }
 // }
 
+// everything the communication and the input reports share
+#[derive(Clone)]
+struct ControllerState {
+    // the input sources, combined again for every report
+    input: Arbiter,
+    macros: Macros,
+    tas: Tas,
+    imu: Arc<Mutex<Imu>>,
+    mcu: Arc<Mutex<Mcu>>,
+    flash: Arc<Mutex<Flash>>,
+    identity: Identity,
+    clock: Clock,
+    pacing: PacingConfig,
+    jitter: Arc<Mutex<Jitter>>,
+    link: Link,
+    feedback: ControllerFeedback,
+    // stick calibration in flash, changes when the console recalibrates
+    calibration: Arc<Mutex<Calibration>>,
+    rumble: RumbleEvents,
+}
+
+impl ControllerState {
+    // buttons and sticks as the emulated device reports them
+    fn input_buf(&self) -> [u8; 11] {
+        self.buf_for(&self.input.combine())
+    }
+
+    fn buf_for(&self, input: &Input) -> [u8; 11] {
+        input
+            .for_device(self.identity.device_type, self.identity.orientation)
+            .get_buf(self.identity.device_type, &self.calibration.lock().unwrap())
+    }
+
+    // after the host wrote to flash, it may have been a new stick calibration
+    fn reload_calibration(&self) {
+        let calibration = Calibration::from_flash(&self.flash.lock().unwrap());
+        let mut current = self.calibration.lock().unwrap();
+        if *current != calibration {
+            println!("Stick calibration: {:?}", calibration);
+            *current = calibration;
+        }
+    }
+
+    fn simple_for(&self, input: &Input) -> InputReport {
+        let (buttons, hat, sticks) = input
+            .for_device(self.identity.device_type, self.identity.orientation)
+            .get_simple(self.identity.device_type);
+        InputReport::Simple { buttons, hat, sticks }
+    }
+
+    // nothing pressed, sticks centered and the controller at rest
+    fn neutral_report(&self, mode: ReportMode) -> InputReport {
+        match mode {
+            ReportMode::Simple => self.simple_for(&Input::new()),
+            ReportMode::Standard | ReportMode::Nfc => InputReport::Standard {
+                timer: self.clock.timer(),
+                input: self.buf_for(&Input::new()),
+                imu: [0u8; IMU_SIZE],
+            },
+        }
+    }
+}
+
 // start input report
 fn start_input_sending(
-    writable: Arc<Mutex<Box<impl Write + Send + 'static>>>,
-    input: Arc<Mutex<Input>>,
-    count: Arc<Mutex<u8>>,
-    stop_signal: Arc<Mutex<bool>>,
-) {
-    let interval = Duration::from_millis(30);
-    let mut next = Instant::now() + interval;
+    writer: Sender<InputReport>,
+    state: ControllerState,
+    shutdown: Shutdown,
+) -> tokio::task::JoinHandle<()> {
+    let mut pacer = Pacer::new(state.pacing.interval(), Arc::clone(&state.jitter));
+    let mut last_log = Instant::now();
+    let mut last_simple = None;
 
     tokio::task::spawn(async move {
-        println!("start input sending");
+        println!("start input sending every {:?}", state.pacing.interval());
 
         loop {
-            if *stop_signal.lock().unwrap() {
-                break;
+            tokio::select! {
+                _ = pacer.tick() => {}
+                _ = shutdown.stopped() => {
+                    // don't leave the console with buttons held down
+                    let _ = writer.send(state.neutral_report(state.feedback.report_mode()));
+                    break;
+                }
             }
 
-            write(
-                &mut *writable.lock().unwrap(),
-                0x30,
-                *count.lock().unwrap(),
-                &input.lock().unwrap().get_buf(),
-            ).unwrap();
+            // macros count reports, not time
+            state.macros.tick();
+            let mode = state.feedback.report_mode();
+            let report = match mode {
+                ReportMode::Standard | ReportMode::Nfc => {
+                    last_simple = None;
+                    let timer = state.clock.timer();
+                    // a TAS frame is a full report
+                    state.tas.tick();
+                    let combined = state.input.combine();
+                    state.tas.record(&combined);
+                    let input = state.buf_for(&combined);
+                    let imu = state.imu.lock().unwrap().report();
+                    if mode == ReportMode::Nfc {
+                        let mcu = Box::new(state.mcu.lock().unwrap().report());
+                        InputReport::Nfc { timer, input, imu, mcu }
+                    } else {
+                        InputReport::Standard { timer, input, imu }
+                    }
+                }
+                // like the real controller only report changes
+                ReportMode::Simple => {
+                    let report = state.simple_for(&state.input.combine());
+                    if last_simple.as_ref() == Some(&report) {
+                        continue;
+                    }
+                    last_simple = Some(report.clone());
+                    report
+                }
+            };
+
+            if writer.send(report).is_err() {
+                println!("Failed to send input report: writer stopped");
+                break;
+            }
 
-            tokio::time::sleep(next - Instant::now()).await;
-            next += interval;
+            if last_log.elapsed() >= JITTER_LOG_INTERVAL {
+                println!("Input reports: {}", state.jitter.lock().unwrap());
+                last_log = Instant::now();
+            }
         }
 
-        println!("end input sending");
-    });
+        println!("end input sending: {}", state.jitter.lock().unwrap());
+    })
 }
 
-fn start_counter(count: Arc<Mutex<u8>>, stop_signal: Arc<Mutex<bool>>) {
-    tokio::task::spawn(async move {
-        loop {
-            if *stop_signal.lock().unwrap() {
-                break;
-            }
-
-            let mut c = count.lock().unwrap();
-            *c = c.wrapping_add(1);
+// the running session with the host
+struct Connection {
+    input_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    writer_task: tokio::task::JoinHandle<()>,
+    done: Sender<()>,
+}
 
-            tokio::time::sleep(Duration::from_millis(5));
+impl Connection {
+    // wait for the final input report and everything queued before it to be written,
+    // the shutdown must have been triggered already
+    async fn close(self) {
+        let input_task = self.input_task.lock().unwrap().take();
+        if let Some(t) = input_task {
+            let _ = t.await;
         }
-    });
+
+        drop(self.done);
+        let _ = self.writer_task.await;
+    }
 }
 
-fn connect<T>(
-    file: Arc<Mutex<Box<T>>>,
-    input: Arc<Mutex<Input>>,
-    stop_signal: Arc<Mutex<bool>>,
-) -> Result<(), Box<dyn Error>>
-    where T: Read + Write + Send + 'static {
+// apply a subcommand, unknown ones are refused
+fn handle_subcommand(
+    subcommand: &Subcommand,
+    state: &ControllerState,
+) -> Reply {
+    let id = subcommand.id();
 
-    // magic packet
-    write(&mut *file.lock().unwrap(), 0x81, 0x03, [].as_ref())?;
-    write(&mut *file.lock().unwrap(), 0x81, 0x01, [0x00, 0x03].as_ref())?;
+    match subcommand {
+        Subcommand::BluetoothPairing(_) => Reply::with_data(id, vec![0x03, 0x01]),
+        Subcommand::DeviceInfo => Reply::with_data(id, state.identity.device_info().to_vec()),
+        Subcommand::SetReportMode(mode) => match ReportMode::from_id(*mode) {
+            Some(mode) => {
+                state.feedback.update(|f| f.report_mode = mode);
+                Reply::ack(id)
+            }
+            None => {
+                println!("Unsupported report mode {:02X}", mode);
+                Reply::nack(id)
+            }
+        },
+        Subcommand::SpiRead { addr, len } => {
+            match state.flash.lock().unwrap().read(*addr, usize::from(*len)) {
+                Ok(d) => {
+                    println!("Read SPI address: {:05X} {:02X} {:02X?}", addr, len, d);
+
+                    let mut data = addr.to_le_bytes().to_vec();
+                    data.push(*len);
+                    data.extend_from_slice(d);
+                    Reply::with_data(id, data)
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    Reply::nack(id)
+                }
+            }
+        }
+        Subcommand::SpiWrite { addr, data } => {
+            let status = match state.flash.lock().unwrap().write(*addr, data) {
+                Ok(()) => {
+                    println!("Write SPI address: {:05X} {:02X} {:02X?}", addr, data.len(), data);
+                    0x00
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    0x01
+                }
+            };
+            state.reload_calibration();
+            Reply::with_data(id, vec![status])
+        }
+        Subcommand::SpiErase { addr } => {
+            let status = match state.flash.lock().unwrap().erase(*addr) {
+                Ok(()) => {
+                    println!("Erase SPI sector: {:05X}", addr);
+                    0x00
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    0x01
+                }
+            };
+            state.reload_calibration();
+            Reply::with_data(id, vec![status])
+        }
+        Subcommand::McuReset => {
+            state.mcu.lock().unwrap().reset();
+            Reply::ack(id)
+        }
+        Subcommand::McuConfig(data) => {
+            Reply::with_data(id, state.mcu.lock().unwrap().configure(data).to_vec())
+        }
+        Subcommand::McuState(arg) => {
+            state.mcu.lock().unwrap().set_state(*arg);
+            Reply::ack(id)
+        }
+        Subcommand::EnableImu(enabled) => {
+            let enabled = *enabled != 0x00;
+            state.imu.lock().unwrap().enabled = enabled;
+            state.feedback.update(|f| f.imu = enabled);
+            Reply::ack(id)
+        }
+        Subcommand::ImuSensitivity { gyro, accel, .. } => {
+            state.imu.lock().unwrap().set_sensitivity(*gyro, *accel);
+            Reply::ack(id)
+        }
+        Subcommand::WriteImuRegister { addr, value } => {
+            state.imu.lock().unwrap().write_register(*addr, *value);
+            Reply::ack(id)
+        }
+        Subcommand::ReadImuRegister { addr, len } => {
+            // at most 0x20 registers fit in the reply
+            let len = (*len).min(0x20);
+            let mut data = vec![*addr, len];
+            data.extend(state.imu.lock().unwrap().read_registers(*addr, len));
+            Reply::with_data(id, data)
+        }
+        Subcommand::SetPlayerLights(lights) => {
+            state.feedback.update(|f| f.player_lights = *lights);
+            Reply::ack(id)
+        }
+        Subcommand::GetPlayerLights => {
+            Reply::with_data(id, vec![state.feedback.get().player_lights])
+        }
+        Subcommand::SetHomeLight(pattern) => {
+            let pattern = &pattern[..pattern.len().min(feedback::HOME_LIGHT_SIZE)];
+            state.feedback.update(|f| f.home_light = pattern.to_vec());
+            Reply::ack(id)
+        }
+        Subcommand::EnableVibration(enabled) => {
+            state.feedback.update(|f| f.vibration = *enabled != 0x00);
+            Reply::ack(id)
+        }
+        Subcommand::SetShipmentMode(enabled) => {
+            state.feedback.update(|f| f.shipment_mode = *enabled != 0x00);
+            Reply::ack(id)
+        }
+        // no way to tell how long the triggers were held, report them as never pressed
+        Subcommand::TriggerElapsed => Reply::with_data(id, vec![0x00; 14]),
+        // there is always a paired host in the list
+        Subcommand::PageListState => Reply::with_data(id, vec![0x01]),
+        Subcommand::GetVoltage => Reply::with_data(id, BATTERY_VOLTAGE.to_le_bytes().to_vec()),
+        Subcommand::GetState | Subcommand::ResetPairing => Reply::ack(id),
+        // the connection itself is dropped by the session once this is sent
+        Subcommand::SetHciState(_) => Reply::ack(id),
+        Subcommand::Unknown { .. } => {
+            println!("UART unknown request {:02X?}", subcommand);
+            Reply::nack(id)
+        }
+    }
+}
 
-    let counter = Arc::new(Mutex::new(0));
+// what the reader needs to answer the host
+struct Session {
+    writer: Sender<InputReport>,
+    state: ControllerState,
+    input_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    link_config: LinkConfig,
+    shutdown: Shutdown,
+}
 
-    start_counter(Arc::clone(&counter), Arc::clone(&stop_signal));
+impl Session {
+    // magic packet, sent whenever the device has been (re)opened
+    fn announce(&self) -> Result<(), Box<dyn Error>> {
+        self.writer.send(InputReport::Usb { command: 0x03, data: vec![] })?;
+        self.writer.send(InputReport::Usb { command: 0x01, data: vec![0x00, 0x03] })?;
+        self.state.link.set(LinkState::Handshaking);
+        Ok(())
+    }
 
-    tokio::task::spawn(async move {
-        println!("start communication");
-        loop {
+    // answer the host until the device goes away or we shut down
+    fn serve(&self, readable: &mut impl Read) {
+        while !self.shutdown.is_stopped() {
             let mut buf = [0u8; 128];
-            let mut f = file.lock().unwrap();
-            let n = (*f).read(&mut buf).unwrap();
+            let n = match readable.read(&mut buf) {
+                Ok(0) => {
+                    println!("Device closed");
+                    return;
+                }
+                Ok(n) => n,
+                // nothing from the host yet
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if link::is_shutdown(&e) => {
+                    println!("Device shut down, the host went away");
+                    return;
+                }
+                Err(e) => {
+                    println!("Failed to read: {}", e);
+                    return;
+                }
+            };
 
             println!("Read: {:02X?}", &buf[..n]);
-            match buf[0] {
-                0x80 => match buf[1] {
-                    0x01 => {
-                        write(
-                            &mut *f,
-                            0x81,
-                            buf[1],
-                            [0x00, 0x03, 0x00, 0x00, 0x5e, 0x00, 0x53, 0x5e].as_ref(),
-                        ).unwrap();
-                    }
-                    0x02 | 0x03 => {
-                        write(&mut *f, 0x81, buf[1], [].as_ref()).unwrap();
-                    }
-                    0x04 => {
-                        start_input_sending(
-                            Arc::clone(&file),
-                            Arc::clone(&input),
-                            Arc::clone(&counter),
-                            Arc::clone(&stop_signal),
-                        );
-                    }
-                    0x05 => {
-                        *stop_signal.lock().unwrap() = true;
-                    }
-                    _ => {
-                        println!("Received unknown command {:02X}", buf[0]);
-                    }
-                },
-                0x01 => match buf[10] {
-                    0x01 => {
-                        uart(
-                            &mut *f,
-                            &*input.lock().unwrap(),
-                            *counter.lock().unwrap(),
-                            true,
-                            buf[10],
-                            &[0x03, 0x01],
-                        ).unwrap();
-                    }
-                    0x02 => {
-                        uart(
-                            &mut *f,
-                            &*input.lock().unwrap(),
-                            *counter.lock().unwrap(),
-                            true,
-                            buf[10],
-                            &[0x03, 0x48, 0x03, 0x02, 0x5e, 0x53, 0x00, 0x5e, 0x00, 0x00, 0x03, 0x01],
-                        ).unwrap();
+            let result = match OutputReport::parse(&buf[..n]) {
+                Ok(r) => self.handle(r),
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = result {
+                println!("Failed to reply: {}", e);
+            }
+        }
+    }
+
+    fn handle(&self, report: OutputReport) -> Result<(), Box<dyn Error>> {
+        let state = &self.state;
+        if let OutputReport::Subcommand { rumble, .. } | OutputReport::Rumble { rumble, .. } | OutputReport::Mcu { rumble, .. } = &report {
+            // a real controller stays still until vibration is enabled with 0x48
+            if state.feedback.get().vibration {
+                state.rumble.publish(state.clock.elapsed(), rumble);
+            }
+        }
+
+        match report {
+            OutputReport::Usb(command) => match command {
+                UsbCommand::Status => {
+                    // the host starts over, e.g. after waking up
+                    self.stop_streaming();
+                    state.link.set(LinkState::Handshaking);
+                    self.writer.send(InputReport::Usb {
+                        command: command.id(),
+                        data: state.identity.status().to_vec(),
+                    })?;
+                }
+                UsbCommand::Handshake => {
+                    if state.link.get() == LinkState::Handshaking {
+                        state.link.set(LinkState::Paired);
                     }
-                    0x03 | 0x08 | 0x30 | 0x38 | 0x40 | 0x41 | 0x48 => {
-                        uart(
-                            &mut *f,
-                            &*input.lock().unwrap(),
-                            *counter.lock().unwrap(),
-                            true,
-                            buf[10],
-                            &[],
-                        ).unwrap();
+                    self.writer.send(InputReport::Usb { command: command.id(), data: vec![] })?;
+                }
+                UsbCommand::HighSpeed => {
+                    self.writer.send(InputReport::Usb { command: command.id(), data: vec![] })?;
+                }
+                UsbCommand::ForceUsb => {
+                    let task = start_input_sending(
+                        self.writer.clone(),
+                        state.clone(),
+                        self.shutdown.clone(),
+                    );
+                    if let Some(old) = self.input_task.lock().unwrap().replace(task) {
+                        old.abort();
                     }
-                    0x04 => {
-                        uart(
-                            &mut *f,
-                            &*input.lock().unwrap(),
-                            *counter.lock().unwrap(),
-                            true,
-                            buf[10],
-                            &[],
-                        ).unwrap();
+                    state.link.set(LinkState::Streaming);
+                }
+                UsbCommand::DisableForceUsb => {
+                    self.stop_streaming();
+                    state.link.set(LinkState::Suspended);
+                    if self.link_config.wake {
+                        self.start_wake();
                     }
-                    0x10 => {
-                        let data = SPI_ROM_DATA.get(&buf[12]);
-                        match data {
-                            Some(d) => {
-                                let mut uart_data = buf[11..16].to_vec();
-                                uart_data.append(&mut d[usize::from(buf[11])..usize::from(buf[11] + buf[15])].to_vec());
-
-                                uart(
-                                    &mut *f,
-                                    &*input.lock().unwrap(),
-                                    *counter.lock().unwrap(),
-                                    true,
-                                    buf[10],
-                                    uart_data.as_ref(),
-                                ).unwrap();
-
-                                println!("Read SPI address: {:02X} {:02X} {:0X} {:02X?}", buf[12], buf[11], buf[15], &d[usize::from(buf[11])..usize::from(buf[11] + buf[15])])
-                            }
-                            None => {
-                                uart(
-                                    &mut *f,
-                                    &*input.lock().unwrap(),
-                                    *counter.lock().unwrap(),
-                                    false,
-                                    buf[10],
-                                    &[],
-                                ).unwrap();
-
-                                println!("Unknown SPI address: {:02X} {:02X}", buf[12], buf[15]);
+                }
+                UsbCommand::Unknown(id) => {
+                    println!("Received unknown command {:02X}", id);
+                }
+            },
+            OutputReport::Subcommand { subcommand, .. } => {
+                let r = handle_subcommand(&subcommand, state);
+                reply(&self.writer, state, state.clock.timer(), r)?;
+                if let Subcommand::SetHciState(mode) = subcommand {
+                    self.set_hci_state(mode)?;
+                }
+            }
+            OutputReport::Mcu { data, .. } => {
+                // answered in the MCU payload of the following 0x31 reports
+                if state.feedback.report_mode() != ReportMode::Nfc {
+                    println!("MCU request outside of NFC/IR mode {:02X?}", data.first());
+                }
+                state.mcu.lock().unwrap().request(&data);
+            }
+            // rumble only, published above
+            OutputReport::Rumble { .. } => {}
+        }
+        Ok(())
+    }
+
+    // subcommand 0x06, the controller goes to sleep or reboots after the ack
+    fn set_hci_state(&self, mode: u8) -> Result<(), Box<dyn Error>> {
+        self.stop_streaming();
+        match mode {
+            // disconnect, the host has to start over with a status request
+            0x00 => {
+                println!("HCI: disconnect");
+                self.state.link.set(LinkState::Disconnected);
+            }
+            // reboot and reconnect or pair, we come back as if just plugged in
+            _ => {
+                println!("HCI: reboot {:02X}", mode);
+                self.announce()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stop_streaming(&self) {
+        if let Some(t) = self.input_task.lock().unwrap().take() {
+            t.abort();
+        }
+    }
+
+    // press HOME once there is input while the host is asleep
+    fn start_wake(&self) {
+        let writer = self.writer.clone();
+        let state = self.state.clone();
+
+        tokio::task::spawn(async move {
+            let idle = state.buf_for(&Input::new());
+            loop {
+                tokio::time::sleep(state.pacing.interval()).await;
+                if state.link.get() != LinkState::Suspended {
+                    return;
+                }
+                if state.input_buf() != idle {
+                    break;
+                }
+            }
+
+            println!("Waking the console");
+            for home in [true, false] {
+                let mut input = Input::new();
+                input.home = home;
+                let report = InputReport::Standard {
+                    timer: state.clock.timer(),
+                    input: state.buf_for(&input),
+                    imu: [0u8; IMU_SIZE],
+                };
+                if writer.send(report).is_err() {
+                    return;
+                }
+                tokio::time::sleep(WAKE_PRESS).await;
+            }
+        });
+    }
+}
+
+// `open` gives the two halves of the device and is called again to reopen it
+fn connect<R, W, F>(
+    mut open: F,
+    state: ControllerState,
+    link_config: LinkConfig,
+    shutdown: Shutdown,
+) -> Result<Connection, Box<dyn Error>>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+        F: FnMut() -> io::Result<(R, W)> + Send + 'static {
+
+    let (readable, writable) = open()?;
+    let device = Arc::new(Mutex::new(Some(writable)));
+    let (done, done_receiver) = unbounded();
+    let (writer, writer_task) = start_writer(Arc::clone(&device), state.link.clone(), done_receiver);
+
+    let session = Session {
+        writer,
+        state,
+        input_task: Arc::new(Mutex::new(None)),
+        link_config,
+        shutdown: shutdown.clone(),
+    };
+    let connection = Connection {
+        input_task: Arc::clone(&session.input_task),
+        writer_task,
+        done,
+    };
+    session.announce()?;
+
+    // reads block, keep them off the async workers and away from the writer
+    tokio::task::spawn_blocking(move || {
+        println!("start communication");
+        let mut readable = Some(readable);
+
+        while !shutdown.is_stopped() {
+            let mut r = match readable.take() {
+                Some(r) => r,
+                None if !link_config.reconnect => {
+                    shutdown.stop(StopReason::Disconnected);
+                    break;
+                }
+                None => {
+                    std::thread::sleep(link_config.retry());
+                    match open() {
+                        Ok((r, w)) => {
+                            println!("Reopened the device");
+                            *device.lock().unwrap() = Some(w);
+                            if let Err(e) = session.announce() {
+                                println!("Failed to announce: {}", e);
                             }
+                            r
+                        }
+                        Err(e) => {
+                            println!("Failed to reopen the device: {}", e);
+                            continue;
                         }
                     }
-                    0x21 => {
-                        uart(
-                            &mut *f,
-                            &*input.lock().unwrap(),
-                            *counter.lock().unwrap(),
-                            true,
-                            buf[10],
-                            &[0x01, 0x00, 0xff, 0x00, 0x03, 0x00, 0x05, 0x01],
-                        ).unwrap();
-                    }
-                    _ => {
-                        println!("UART unknown request {:02X} {:02X?}", buf[10], buf);
-                    }
-                },
-                0x00 | 0x10 | _ => {
-                    println!("Unknown request {:02X}", buf[0]);
                 }
+            };
+
+            session.serve(&mut r);
+            if shutdown.is_stopped() {
+                break;
             }
+
+            session.stop_streaming();
+            *device.lock().unwrap() = None;
+            session.state.link.set(LinkState::Disconnected);
         }
+        println!("end communication");
     });
 
-    Ok(())
+    Ok(connection)
 }
 
-// fn main() -> () {
-//     let args: Vec<String> = env::args().collect();
-//     let local_ip = local_ip().unwrap();
-//     let port = match args.get(1) {
-//         Some(s) => s.clone(),
-//         None => String::from("12351"),
-//     };
-//     let addr = format!("{:?}:{}", local_ip, port);
-//
-//     let socket = UdpSocket::bind(&addr).expect("couldn't bind socket");
-//     println!("Successfully {} binding socket", &addr);
-//     println!("Listening...");
-//
-//     let mut buff = Cursor::new([0u8; 2048]);
-//
-//     // csv
-//     let mut wtr = WriterBuilder::new()
-//         .has_headers(true)
-//         .from_path("output.csv")
-//         .unwrap();
-//
-//     let start = Instant::now();
-//     loop {
-//         socket
-//             .recv_from(buff.get_mut())
-//             .expect("didn't receive data");
-//
-//         match mocopi_parser::parse(buff.get_mut()) {
-//             Ok(r) => match r {
-//                 SkeletonOrFrame::Skeleton(s) => {
-//                     dbg!(s);
-//                 }
-//                 SkeletonOrFrame::Frame(f) => {
-//                     dbg!(f.frame.bones.first());
-//                     let end = start.elapsed();
-//                     for b in f.frame.bones {
-//                         wtr.serialize(Row {
-//                             id: &b.id.to_string().as_str(),
-//                             time: format!("{}.{:03}", end.as_secs(), end.subsec_millis()).as_str(),
-//                             rot_x: b.trans.rot.x,
-//                             rot_y: b.trans.rot.y,
-//                             rot_z: b.trans.rot.z,
-//                             rot_w: b.trans.rot.w,
-//                             pos_x: b.trans.pos.x,
-//                             pos_y: b.trans.pos.y,
-//                             pos_z: b.trans.pos.z,
-//                         })
-//                             .unwrap();
-//                     }
-//                 }
-//             },
-//             Err(_) => {
-//                 dbg!("parse error");
-//             }
-//         }
-//     }
-// }
-
 // fn set_input(input: &mut u8) {
 //     *input += 1;
 //     thread::spawn(move || {
@@ -736,35 +1340,238 @@ fn connect<T>(
 //     });
 // }
 
+// everything the emulated controller needs, built from the config
+fn build_state(config: &Config) -> Result<ControllerState, Box<dyn Error>> {
+    let mut flash = Flash::from_config(&config.flash)?;
+    config.identity.apply(&mut flash)?;
+
+    let feedback = ControllerFeedback::new();
+    feedback::start_logging(&feedback);
+
+    let rumble = RumbleEvents::new();
+    if config.rumble.log {
+        rumble::start_logging(&rumble);
+    }
+
+    let arbiter = Arbiter::new(&config.arbiter);
+    let macros = Macros::new(&config.macros, &arbiter, config.pacing.interval())?;
+    let tas = Tas::new(&config.tas, &arbiter)?;
+
+    Ok(ControllerState {
+        input: arbiter,
+        macros,
+        tas,
+        imu: Arc::new(Mutex::new(Imu::new(&config.imu))),
+        mcu: Arc::new(Mutex::new(Mcu::new())),
+        calibration: Arc::new(Mutex::new(Calibration::from_flash(&flash))),
+        flash: Arc::new(Mutex::new(flash)),
+        identity: config.identity.clone(),
+        clock: Clock::new(),
+        pacing: config.pacing,
+        jitter: Arc::new(Mutex::new(Jitter::default())),
+        link: Link::new(),
+        feedback,
+        rumble,
+    })
+}
+
+// a device that can't be reopened, like the simulated console's pipe
+fn open_once<R, W>(halves: (R, W)) -> impl FnMut() -> io::Result<(R, W)> {
+    let mut halves = Some(halves);
+    move || halves.take().ok_or_else(|| io::Error::new(ErrorKind::NotFound, "the device can't be reopened"))
+}
+
+// run the console's side of the handshake against the emulated controller
+async fn simulate(config: &Config) -> Result<simulator::Summary, Box<dyn Error>> {
+    let (controller, console) = simulator::duplex();
+    let recorder = config.capture.path.as_ref().map(Recorder::create).transpose()?.map(|r| Arc::new(Mutex::new(r)));
+    let shutdown = Shutdown::new();
+    let state = build_state(config)?;
+    let jitter = Arc::clone(&state.jitter);
+    let connection = connect(
+        open_once((Recorded::new(controller.clone(), recorder.clone()), Recorded::new(controller, recorder))),
+        state,
+        LinkConfig { reconnect: false, ..config.link },
+        shutdown.clone(),
+    )?;
+
+    let host = simulator::Host::new(console, config.identity.clone());
+    let result = tokio::task::spawn_blocking(move || host.run(SIMULATED_REPORTS)).await?;
+    shutdown.stop(StopReason::Finished);
+    connection.close().await;
+    println!("Input reports: {}", jitter.lock().unwrap());
+    Ok(result?)
+}
+
+// feed the host side of a capture or usbmon pcap to the emulator and compare the replies
+async fn replay(path: &str, config: &Config) -> Result<Vec<capture::Mismatch>, Box<dyn Error>> {
+    let records = capture::load(path)?;
+    let (controller, console) = simulator::duplex();
+    let shutdown = Shutdown::new();
+    let connection = connect(
+        open_once((controller.clone(), controller)),
+        build_state(config)?,
+        LinkConfig { reconnect: false, ..config.link },
+        shutdown.clone(),
+    )?;
+
+    let expected = records.clone();
+    let actual = tokio::task::spawn_blocking(move || capture::replay(console, &records)).await?;
+    shutdown.stop(StopReason::Finished);
+    connection.close().await;
+    Ok(capture::compare(&expected, &actual))
+}
+
 #[tokio::main]
 async fn main() {
-    let target = env::args().nth(1).unwrap();
-    let file = Arc::new(Mutex::new(Box::new(File::options().read(true).write(true).open(target).unwrap())));
-    let input = Arc::new(Mutex::new(Input::new()));
-    let stop_signal = Arc::new(Mutex::new(false));
-
-    connect(
-        Arc::clone(&file),
-        Arc::clone(&input),
-        Arc::clone(&stop_signal),
+    let args: Vec<String> = env::args().collect();
+    let target = args.get(1).unwrap();
+    // `replay <capture> [config]` takes the config one position later
+    let config_arg = if target == "replay" { 3 } else { 2 };
+    let config = match args.get(config_arg) {
+        Some(path) => Config::load(path).unwrap(),
+        None => Config::default(),
+    };
+
+    if target == "replay" {
+        match replay(args.get(2).expect("no capture given"), &config).await {
+            Ok(mismatches) if mismatches.is_empty() => {
+                println!("Replay matched the capture");
+                return;
+            }
+            Ok(mismatches) => {
+                for m in &mismatches {
+                    m.print();
+                }
+                println!("Replay differs from the capture in {} replies", mismatches.len());
+                std::process::exit(1);
+            }
+            Err(e) => {
+                println!("Replay failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // `simulate [config]` checks the handshake without a console attached
+    if target == "simulate" {
+        match simulate(&config).await {
+            Ok(summary) => {
+                println!("Simulation passed: {:?}", summary);
+                return;
+            }
+            Err(e) => {
+                println!("Simulation failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let csv = config.mocopi.csv.as_ref().map(|path| {
+        WriterBuilder::new()
+            .has_headers(true)
+            .from_path(path)
+            .unwrap()
+    });
+
+    let recorder = config.capture.path.as_ref().map(|path| Arc::new(Mutex::new(Recorder::create(path).unwrap())));
+    let path = target.clone();
+    let open = move || {
+        let device = File::options().read(true).write(true).open(&path)?;
+        Ok((Recorded::new(device.try_clone()?, recorder.clone()), Recorded::new(device, recorder.clone())))
+    };
+    let state = build_state(&config).unwrap();
+    println!("Controller {:?} {}", config.identity.device_type, config.identity.mac);
+    let arbiter = state.input.clone();
+    let macros = state.macros.clone();
+    let imu = Arc::clone(&state.imu);
+    let mcu = Arc::clone(&state.mcu);
+    let amiibo = match &config.amiibo.dir {
+        Some(dir) => mcu::list_amiibo(dir).unwrap_or_else(|e| {
+            println!("No amiibo from {}: {}", dir, e);
+            vec![]
+        }),
+        None => vec![],
+    };
+    let shutdown = Shutdown::new();
+    lifecycle::handle_signals(shutdown.clone()).unwrap();
+
+    let connection = connect(
+        open,
+        state,
+        config.link,
+        shutdown.clone(),
     ).unwrap();
 
-    Command::new("stty")
-        .args(["-F", "/dev/tty", "cbreak", "min", "1"])
-        .output()
-        .unwrap();
+    let local_ip = local_ip().unwrap();
+    let addr = format!("{:?}:{}", local_ip, config.mocopi.port);
+    match tokio::net::UdpSocket::bind(&addr).await {
+        Ok(socket) => {
+            println!("Successfully {} binding socket", &addr);
+
+            let (frame_sender, frame_receiver) = unbounded();
+            mocopi::start_receiver(socket, frame_sender, csv, shutdown.clone());
+            mapping::start_mapping(frame_receiver, config.rules, arbiter.register("motion"), Arc::clone(&imu), macros.clone());
+        }
+        Err(e) => {
+            println!("couldn't bind socket {}: {}", &addr, e);
+            shutdown.stop(StopReason::Failed);
+        }
+    }
 
-    Command::new("stty")
-        .args(["-F", "/dev/tty", "-echo"])
-        .output()
-        .unwrap();
+    for g in &config.gamepads {
+        if let Err(e) = gamepad::start(g, arbiter.register(&g.name), shutdown.clone()) {
+            println!("Gamepad {} disabled: {}", g.device, e);
+        }
+    }
 
-    loop {
+    let mut terminal = None;
+    if let Some(device) = &config.keyboard.device {
+        if let Err(e) = keyboard::start(&config.keyboard, device, arbiter, macros, mcu, amiibo, shutdown.clone()) {
+            println!("Keyboard input disabled: {}", e);
+        }
+    } else {
+        terminal = match Terminal::cbreak() {
+            Ok(t) => Some(t),
+            Err(e) => {
+                println!("Keyboard input disabled: {}", e);
+                None
+            }
+        };
+        if terminal.is_some() && !shutdown.is_stopped() {
+            start_keyboard(arbiter, mcu, amiibo, shutdown.clone());
+        }
+    }
+
+    let reason = shutdown.stopped().await;
+    connection.close().await;
+    if let Some(t) = terminal.as_mut() {
+        t.restore();
+    }
+
+    println!("Stopped: {:?}", reason);
+    // blocked reads would keep the runtime from shutting down
+    std::process::exit(reason.exit_code());
+}
+
+// single key presses from the terminal when there is no evdev keyboard, q quits and m switches motion on and off
+fn start_keyboard(arbiter: Arbiter, mcu: Arc<Mutex<Mcu>>, amiibo: Vec<PathBuf>, shutdown: Shutdown) {
+    let input = arbiter.register("keyboard");
+    let mut next_amiibo = 0;
+
+    tokio::task::spawn_blocking(move || loop {
         let mut buf = [0u8; 1];
-        stdin().read(&mut buf).unwrap();
+        if stdin().read_exact(&mut buf).is_err() {
+            println!("end keyboard input");
+            break;
+        }
 
-        println!("pushed {}", buf[0].to_string());
+        println!("pushed {}", buf[0]);
         match buf[0] {
+            b'q' => {
+                shutdown.stop(StopReason::Finished);
+                break;
+            }
             b'w' => {
                 let i = Arc::clone(&input);
                 i.lock().unwrap().up = true;
@@ -778,7 +1585,7 @@ async fn main() {
                 i.lock().unwrap().left = true;
                 tokio::task::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(100)).await;
-                    i.lock().unwrap().left = true;
+                    i.lock().unwrap().left = false;
                 });
             }
             b's' => {
@@ -797,17 +1604,215 @@ async fn main() {
                     i.lock().unwrap().right = false;
                 });
             }
+            b'm' => arbiter.toggle("motion"),
+            // tap the next amiibo for a moment
+            b'n' => {
+                if let Some(path) = amiibo.get(next_amiibo) {
+                    tap_amiibo(&mcu, path);
+                    next_amiibo = (next_amiibo + 1) % amiibo.len();
+                }
+            }
             _ => {}
         };
+    });
+}
+
+// place an amiibo on the reader for a few seconds
+fn tap_amiibo(mcu: &Arc<Mutex<Mcu>>, path: &Path) {
+    match Amiibo::load(path) {
+        Ok(a) => {
+            let m = Arc::clone(mcu);
+            let tap = m.lock().unwrap().tap(a);
+            tokio::task::spawn(async move {
+                tokio::time::sleep(AMIIBO_TAP).await;
+                m.lock().unwrap().remove(tap);
+            });
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> (Session, Receiver<InputReport>) {
+        let (writer, reports) = unbounded();
+        let session = Session {
+            writer,
+            state: build_state(&Config::default()).unwrap(),
+            input_task: Arc::new(Mutex::new(None)),
+            link_config: LinkConfig::default(),
+            shutdown: Shutdown::new(),
+        };
+        (session, reports)
+    }
+
+    fn send_subcommand(session: &Session, subcommand: Subcommand) {
+        session.handle(OutputReport::Subcommand { timer: 0, rumble: [0; 8], subcommand }).unwrap();
     }
 
-    // Command::new("stty")
-    //     .args(["-F", "/dev/tty", "-cbreak"])
-    //     .output()
-    //     .unwrap();
-    //
-    // Command::new("stty")
-    //     .args(["-F", "/dev/tty", "echo"])
-    //     .output()
-    //     .unwrap();
+    // the replies to subcommands, the announcement and what else was written
+    fn written(reports: &Receiver<InputReport>) -> Vec<(u8, u8)> {
+        reports
+            .try_iter()
+            .map(|r| match r {
+                InputReport::Reply { reply, .. } => (0x21, reply.id),
+                InputReport::Usb { command, .. } => (0x81, command),
+                r => (r.id(), 0x00),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn joycon_buttons() {
+        let calibration = Calibration::from_flash(&Flash::new());
+        let mut input = Input::new();
+        input.a = true;
+        input.l = true;
+        input.sl = true;
+        input.minus = true;
+        input.plus = true;
+        input.home = true;
+        input.stick_r.x = 1.0;
+
+        // each Joy-Con only has its half of the buttons and its own stick
+        let left = input.get_buf(DeviceType::Left, &calibration);
+        assert_eq!(left[..4], [0x8e, 0x00, 0x01, 0x60]);
+        assert_eq!(left[7..10], [0x00; 3]);
+
+        let right = input.get_buf(DeviceType::Right, &calibration);
+        assert_eq!(right[..4], [0x8e, 0x28, 0x12, 0x00]);
+        assert_eq!(right[4..7], [0x00; 3]);
+        assert_ne!(right[7..10], [0x00; 3]);
+    }
+
+    #[test]
+    fn sideways_joycons() {
+        let mut input = Input::new();
+        input.a = true;
+        input.x = true;
+        input.zl = true;
+        input.stick_l.press = true;
+        input.stick_l.x = 1.0;
+
+        let left = input.for_device(DeviceType::Left, Orientation::Sideways);
+        assert!(left.down && left.right && left.sl && !left.zl);
+        assert_eq!((left.stick_l.x, left.stick_l.y), (0.0, -1.0));
+
+        let right = input.for_device(DeviceType::Right, Orientation::Sideways);
+        assert!(right.x && right.y && right.sl && right.stick_r.press);
+        assert_eq!((right.stick_r.x, right.stick_r.y), (0.0, 1.0));
+
+        // held upright, nothing moves
+        let upright = input.for_device(DeviceType::Left, Orientation::Vertical);
+        assert!(upright.a && upright.zl && !upright.down);
+    }
+
+    #[tokio::test]
+    async fn status_replies() {
+        let (session, _) = session();
+        let state = &session.state;
+
+        assert_eq!(
+            handle_subcommand(&Subcommand::TriggerElapsed, state),
+            Reply { ack: 0x83, id: 0x04, data: vec![0x00; 14] }
+        );
+        assert_eq!(handle_subcommand(&Subcommand::PageListState, state), Reply { ack: 0x80, id: 0x05, data: vec![0x01] });
+        assert_eq!(handle_subcommand(&Subcommand::GetVoltage, state), Reply { ack: 0xd0, id: 0x50, data: vec![0x83, 0x06] });
+    }
+
+    #[tokio::test]
+    async fn imu_registers() {
+        let (session, _) = session();
+        let state = &session.state;
+
+        let before = handle_subcommand(&Subcommand::ReadImuRegister { addr: 0x10, len: 3 }, state);
+        assert_eq!((before.ack, &before.data[..2]), (0xc0, &[0x10, 0x03][..]));
+
+        let written = handle_subcommand(&Subcommand::WriteImuRegister { addr: 0x11, value: 0x5a }, state);
+        assert_eq!(written, Reply::ack(0x42));
+        let after = handle_subcommand(&Subcommand::ReadImuRegister { addr: 0x10, len: 3 }, state);
+        assert_eq!(after.data, [0x10, 0x03, before.data[2], 0x5a, before.data[4]]);
+
+        // at most 0x20 registers, past the last one they read as zero
+        let end = handle_subcommand(&Subcommand::ReadImuRegister { addr: 0x70, len: 0x30 }, state);
+        assert_eq!(end.data.len(), 2 + 0x20);
+        assert_eq!(end.data[1], 0x20);
+        assert_eq!(end.data[2 + 0x10..], [0x00; 0x10]);
+    }
+
+    #[tokio::test]
+    async fn feedback_subcommands() {
+        let (session, _) = session();
+        let state = &session.state;
+
+        assert_eq!(handle_subcommand(&Subcommand::SetPlayerLights(0x09), state), Reply::ack(0x30));
+        assert_eq!(state.feedback.get().player(), Some(5));
+        assert_eq!(handle_subcommand(&Subcommand::GetPlayerLights, state), Reply { ack: 0xb0, id: 0x31, data: vec![0x09] });
+
+        // the padding after the pattern is dropped
+        let mut pattern = vec![0x00; 35];
+        pattern[1] = 0xa0;
+        handle_subcommand(&Subcommand::SetHomeLight(pattern), state);
+        assert_eq!(state.feedback.get().home_light.len(), feedback::HOME_LIGHT_SIZE);
+        assert_eq!(state.feedback.get().home_light_intensity(), 10);
+
+        handle_subcommand(&Subcommand::EnableImu(0x01), state);
+        assert!(state.feedback.get().imu);
+        assert!(state.imu.lock().unwrap().enabled);
+        handle_subcommand(&Subcommand::EnableImu(0x00), state);
+        assert!(!state.feedback.get().imu);
+        assert!(!state.imu.lock().unwrap().enabled);
+
+        assert!(!state.feedback.get().vibration);
+        handle_subcommand(&Subcommand::EnableVibration(0x01), state);
+        assert!(state.feedback.get().vibration);
+    }
+
+    #[tokio::test]
+    async fn link_states() {
+        let (session, _reports) = session();
+        let link = &session.state.link;
+        let usb = |command| session.handle(OutputReport::Usb(command)).unwrap();
+
+        // a handshake out of order doesn't pair
+        usb(UsbCommand::Handshake);
+        assert_eq!(link.get(), LinkState::Disconnected);
+
+        usb(UsbCommand::Status);
+        assert_eq!(link.get(), LinkState::Handshaking);
+        usb(UsbCommand::Handshake);
+        assert_eq!(link.get(), LinkState::Paired);
+        usb(UsbCommand::HighSpeed);
+        usb(UsbCommand::Handshake);
+        assert_eq!(link.get(), LinkState::Paired);
+
+        usb(UsbCommand::ForceUsb);
+        assert_eq!(link.get(), LinkState::Streaming);
+        assert!(session.input_task.lock().unwrap().is_some());
+
+        // the console goes to sleep
+        usb(UsbCommand::DisableForceUsb);
+        assert_eq!(link.get(), LinkState::Suspended);
+        assert!(session.input_task.lock().unwrap().is_none());
+
+        // and starts over when it wakes up
+        usb(UsbCommand::Status);
+        assert_eq!(link.get(), LinkState::Handshaking);
+    }
+
+    #[tokio::test]
+    async fn hci_disconnect_and_reboot() {
+        let (session, reports) = session();
+
+        send_subcommand(&session, Subcommand::SetHciState(0x00));
+        assert_eq!(written(&reports), [(0x21, 0x06)]);
+        assert_eq!(session.state.link.get(), LinkState::Disconnected);
+
+        // a reboot comes back announcing itself like a freshly plugged in controller
+        send_subcommand(&session, Subcommand::SetHciState(0x01));
+        assert_eq!(written(&reports), [(0x21, 0x06), (0x81, 0x03), (0x81, 0x01)]);
+        assert_eq!(session.state.link.get(), LinkState::Handshaking);
+    }
 }
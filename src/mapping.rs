@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::Receiver;
+use mocopi_parser::{BoneTrans, FramePacket};
+use serde::Deserialize;
+use crate::{Input, InputField};
+use crate::imu::Imu;
+use crate::macros::Macros;
+use crate::quaternion::Quaternion;
+
+pub const BONE_ROOT: u16 = 0;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+// a quaternion has a fourth
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    X,
+    Y,
+    Z,
+    W,
+}
+
+// the value a rule derives from a bone
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Quantity {
+    // raw quaternion component of the local rotation
+    Rotation { axis: Component },
+    // euler angle of the local rotation in degrees
+    Euler { axis: Axis },
+    // rotation angle of the joint relative to its parent in degrees
+    JointAngle,
+    // distance from the neutral pose in meters
+    PositionDelta { axis: Axis },
+    // speed in meters per second, along one axis or overall
+    Velocity { axis: Option<Axis> },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    pub bone: u16,
+    pub quantity: Quantity,
+    // what the rule holds while it is on, a rule may also only run a macro
+    #[serde(default)]
+    pub target: Option<InputField>,
+    // macro started each time the rule turns on
+    #[serde(default)]
+    pub run: Option<String>,
+    // value at which the rule turns on
+    pub threshold: f64,
+    // the rule turns off again below `threshold - hysteresis`
+    #[serde(default)]
+    pub hysteresis: f64,
+    // how long a change has to last before it is applied
+    #[serde(default)]
+    pub debounce_ms: u64,
+    // value at full stick deflection, only used for stick axes
+    // the stick leaves the centre at `threshold` and reaches the edge at `range`
+    #[serde(default = "default_range")]
+    pub range: f64,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+fn default_range() -> f64 {
+    1.0
+}
+
+impl Rule {
+    // walk by moving the hips, jump by rising on the toes
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self {
+                bone: BONE_ROOT,
+                quantity: Quantity::PositionDelta { axis: Axis::X },
+                target: Some(InputField::StickLX),
+                run: None,
+                threshold: 0.03,
+                hysteresis: 0.01,
+                debounce_ms: 0,
+                range: 0.3,
+                invert: false,
+            },
+            Self {
+                bone: BONE_ROOT,
+                quantity: Quantity::PositionDelta { axis: Axis::Z },
+                target: Some(InputField::StickLY),
+                run: None,
+                threshold: 0.03,
+                hysteresis: 0.01,
+                debounce_ms: 0,
+                range: 0.3,
+                invert: false,
+            },
+            Self {
+                bone: BONE_ROOT,
+                quantity: Quantity::PositionDelta { axis: Axis::Y },
+                target: Some(InputField::X),
+                run: None,
+                threshold: 0.08,
+                hysteresis: 0.03,
+                debounce_ms: 50,
+                range: 1.0,
+                invert: false,
+            },
+        ]
+    }
+}
+
+#[derive(Default)]
+struct RuleState {
+    active: bool,
+    pending: Option<(bool, Instant)>,
+}
+
+pub struct Mapper {
+    rules: Vec<Rule>,
+    states: Vec<RuleState>,
+    origin: HashMap<u16, (f64, f64, f64)>,
+    last: HashMap<u16, ((f64, f64, f64), Instant)>,
+}
+
+impl Mapper {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let states = rules.iter().map(|_| RuleState::default()).collect();
+        Self {
+            rules,
+            states,
+            origin: HashMap::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    // the names of the macros whose rules turned on
+    pub fn apply(&mut self, frame: &FramePacket, input: &mut Input, now: Instant) -> Vec<String> {
+        let bones: HashMap<u16, &BoneTrans> = frame.frame.bones.iter().map(|b| (b.id, b)).collect();
+
+        // the first frame is the neutral pose
+        for b in bones.values() {
+            self.origin.entry(b.id).or_insert_with(|| position(b));
+        }
+
+        let mut values: HashMap<InputField, f64> = HashMap::new();
+        let mut started = vec![];
+
+        let Self { rules, states, origin, last } = self;
+
+        for (rule, state) in rules.iter().zip(states.iter_mut()) {
+            let bone = match bones.get(&rule.bone) {
+                Some(b) => b,
+                None => {
+                    // the bone is gone from the frame, let go of whatever the rule holds
+                    *state = RuleState::default();
+                    if let Some(t) = rule.target {
+                        values.entry(t).or_insert(0.0);
+                    }
+                    continue;
+                }
+            };
+
+            let raw = measure(origin, last, rule.quantity, bone, now);
+            let value = if rule.invert { -raw } else { raw };
+            let axis = rule.target.is_some_and(|t| t.is_axis());
+            let magnitude = if axis { value.abs() } else { value };
+            let was_active = state.active;
+
+            let wanted = if state.active {
+                magnitude > rule.threshold - rule.hysteresis
+            } else {
+                magnitude >= rule.threshold
+            };
+
+            if wanted == state.active {
+                state.pending = None;
+            } else {
+                match state.pending {
+                    Some((p, since)) if p == wanted => {
+                        if now.duration_since(since) >= Duration::from_millis(rule.debounce_ms) {
+                            state.active = wanted;
+                            state.pending = None;
+                        }
+                    }
+                    _ => {
+                        state.pending = Some((wanted, now));
+                        if rule.debounce_ms == 0 {
+                            state.active = wanted;
+                            state.pending = None;
+                        }
+                    }
+                }
+            }
+
+            if state.active && !was_active {
+                started.extend(rule.run.clone());
+            }
+            let target = match rule.target {
+                Some(t) => t,
+                None => continue,
+            };
+
+            let out = if !state.active {
+                0.0
+            } else if axis {
+                // scaled from the threshold, so the stick does not jump out of the centre
+                let span = rule.range - rule.threshold;
+                let deflection = if span > 0.0 {
+                    ((value.abs() - rule.threshold) / span).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                deflection.copysign(value)
+            } else {
+                1.0
+            };
+
+            // buttons are OR-ed, sticks take the largest deflection
+            let v = values.entry(target).or_insert(0.0);
+            if out.abs() > v.abs() {
+                *v = out;
+            }
+        }
+
+        for b in bones.values() {
+            last.insert(b.id, (position(b), now));
+        }
+
+        for (field, value) in values {
+            input.set(field, value);
+        }
+        started
+    }
+}
+
+fn measure(
+    origin: &HashMap<u16, (f64, f64, f64)>,
+    last: &HashMap<u16, ((f64, f64, f64), Instant)>,
+    quantity: Quantity,
+    bone: &BoneTrans,
+    now: Instant,
+) -> f64 {
+    let pos = position(bone);
+    let rot = Quaternion::from(&bone.trans.rot);
+
+    match quantity {
+        Quantity::Rotation { axis } => match axis {
+            Component::X => rot.x,
+            Component::Y => rot.y,
+            Component::Z => rot.z,
+            Component::W => rot.w,
+        },
+        Quantity::Euler { axis } => {
+            let (roll, pitch, yaw) = rot.euler();
+            match axis {
+                Axis::X => roll.to_degrees(),
+                Axis::Y => pitch.to_degrees(),
+                Axis::Z => yaw.to_degrees(),
+            }
+        }
+        Quantity::JointAngle => rot.angle().to_degrees(),
+        Quantity::PositionDelta { axis } => {
+            let origin = origin.get(&bone.id).copied().unwrap_or(pos);
+            component(sub(pos, origin), axis)
+        }
+        Quantity::Velocity { axis } => match last.get(&bone.id) {
+            Some((last, at)) => {
+                let dt = now.duration_since(*at).as_secs_f64();
+                if dt <= 0.0 {
+                    return 0.0;
+                }
+                let d = sub(pos, *last);
+                match axis {
+                    Some(a) => component(d, a) / dt,
+                    None => (d.0 * d.0 + d.1 * d.1 + d.2 * d.2).sqrt() / dt,
+                }
+            }
+            None => 0.0,
+        },
+    }
+}
+
+fn position(bone: &BoneTrans) -> (f64, f64, f64) {
+    (bone.trans.pos.x as f64, bone.trans.pos.y as f64, bone.trans.pos.z as f64)
+}
+
+fn sub(a: (f64, f64, f64), b: (f64, f64, f64)) -> (f64, f64, f64) {
+    (a.0 - b.0, a.1 - b.1, a.2 - b.2)
+}
+
+fn component(v: (f64, f64, f64), axis: Axis) -> f64 {
+    match axis {
+        Axis::X => v.0,
+        Axis::Y => v.1,
+        Axis::Z => v.2,
+    }
+}
+
+// apply every received frame to the shared input
+pub fn start_mapping(
+    receiver: Receiver<FramePacket>,
+    rules: Vec<Rule>,
+    input: Arc<Mutex<Input>>,
+    imu: Arc<Mutex<Imu>>,
+    macros: Macros,
+) {
+    thread::spawn(move || {
+        let mut mapper = Mapper::new(rules);
+
+        for frame in receiver {
+            let now = Instant::now();
+            let started = mapper.apply(&frame, &mut input.lock().unwrap(), now);
+            for name in started {
+                macros.start(&name);
+            }
+            imu.lock().unwrap().update(&frame, now);
+        }
+
+        println!("end mapping");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mocopi_parser::{Frame, Head, Info, Position, Rotation, Transform};
+
+    fn frame(bones: &[(u16, [f32; 3])]) -> FramePacket {
+        FramePacket {
+            head: Head { format: "sony motion format".to_string(), ver: 1 },
+            info: Info { addr: 0, port: 0 },
+            frame: Frame {
+                num: 0,
+                time: 0,
+                bones: bones
+                    .iter()
+                    .map(|&(id, [x, y, z])| BoneTrans {
+                        id,
+                        trans: Transform {
+                            rot: Rotation { x: 0.0, y: 0.0, z: 0.0, w: 1.0 },
+                            pos: Position { x, y, z },
+                        },
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn rule(quantity: Quantity, target: InputField, threshold: f64) -> Rule {
+        Rule {
+            bone: BONE_ROOT,
+            quantity,
+            target: Some(target),
+            run: None,
+            threshold,
+            hysteresis: 0.0,
+            debounce_ms: 0,
+            range: 1.0,
+            invert: false,
+        }
+    }
+
+    fn quantity(text: &str) -> Result<Quantity, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Q {
+            quantity: Quantity,
+        }
+        toml::from_str::<Q>(&format!("quantity = {}", text)).map(|q| q.quantity)
+    }
+
+    #[test]
+    fn only_rotation_has_w() {
+        assert!(matches!(quantity(r#"{ kind = "rotation", axis = "w" }"#), Ok(Quantity::Rotation { axis: Component::W })));
+        for kind in ["euler", "position_delta", "velocity"] {
+            let error = quantity(&format!(r#"{{ kind = "{}", axis = "w" }}"#, kind)).unwrap_err();
+            assert!(error.to_string().contains("unknown variant `w`"), "{}: {}", kind, error);
+        }
+        assert!(matches!(quantity(r#"{ kind = "euler", axis = "z" }"#), Ok(Quantity::Euler { axis: Axis::Z })));
+        assert!(matches!(quantity(r#"{ kind = "velocity" }"#), Ok(Quantity::Velocity { axis: None })));
+    }
+
+    #[test]
+    fn missing_bone_releases_the_target() {
+        let rules = Rule::defaults();
+        let mut mapper = Mapper::new(rules);
+        let mut input = Input::new();
+        let now = Instant::now();
+
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0; 3])]), &mut input, now);
+        // jump, held past the debounce
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0, 0.1, 0.0])]), &mut input, now);
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0, 0.1, 0.0])]), &mut input, now + Duration::from_millis(60));
+        assert_eq!(input.get(InputField::X), 1.0);
+
+        mapper.apply(&frame(&[]), &mut input, now + Duration::from_millis(70));
+        assert_eq!(input.get(InputField::X), 0.0);
+
+        // and it comes back like any other change, through the debounce
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0, 0.1, 0.0])]), &mut input, now + Duration::from_millis(80));
+        assert_eq!(input.get(InputField::X), 0.0);
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0, 0.1, 0.0])]), &mut input, now + Duration::from_millis(140));
+        assert_eq!(input.get(InputField::X), 1.0);
+    }
+
+    #[test]
+    fn threshold_with_hysteresis() {
+        let jump = Rule { hysteresis: 0.03, ..rule(Quantity::PositionDelta { axis: Axis::Y }, InputField::X, 0.08) };
+        let mut mapper = Mapper::new(vec![jump]);
+        let mut input = Input::new();
+        let now = Instant::now();
+
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0; 3])]), &mut input, now);
+        let mut at = |y: f32| {
+            mapper.apply(&frame(&[(BONE_ROOT, [0.0, y, 0.0])]), &mut input, now);
+            input.get(InputField::X)
+        };
+        assert_eq!(at(0.07), 0.0);
+        assert_eq!(at(0.09), 1.0);
+        // held between threshold - hysteresis and threshold
+        assert_eq!(at(0.06), 1.0);
+        assert_eq!(at(0.04), 0.0);
+        assert_eq!(at(0.07), 0.0);
+    }
+
+    #[test]
+    fn changes_are_debounced() {
+        let jump = Rule { debounce_ms: 50, ..rule(Quantity::PositionDelta { axis: Axis::Y }, InputField::X, 0.08) };
+        let mut mapper = Mapper::new(vec![jump]);
+        let mut input = Input::new();
+        let now = Instant::now();
+
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0; 3])]), &mut input, now);
+        let mut at = |ms: u64, y: f32| {
+            mapper.apply(&frame(&[(BONE_ROOT, [0.0, y, 0.0])]), &mut input, now + Duration::from_millis(ms));
+            input.get(InputField::X)
+        };
+        assert_eq!(at(0, 0.1), 0.0);
+        assert_eq!(at(30, 0.1), 0.0);
+        assert_eq!(at(50, 0.1), 1.0);
+
+        // a dip shorter than the debounce is ignored
+        assert_eq!(at(60, 0.0), 1.0);
+        assert_eq!(at(80, 0.1), 1.0);
+        assert_eq!(at(120, 0.0), 1.0);
+        assert_eq!(at(160, 0.0), 1.0);
+        assert_eq!(at(170, 0.0), 0.0);
+    }
+
+    #[test]
+    fn inverted_rules() {
+        let lean = Rule { invert: true, ..rule(Quantity::PositionDelta { axis: Axis::Y }, InputField::B, 0.05) };
+        let walk = Rule { invert: true, range: 0.3, ..rule(Quantity::PositionDelta { axis: Axis::X }, InputField::StickLX, 0.1) };
+        let mut mapper = Mapper::new(vec![lean, walk]);
+        let mut input = Input::new();
+        let now = Instant::now();
+
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0; 3])]), &mut input, now);
+        mapper.apply(&frame(&[(BONE_ROOT, [0.2, 0.1, 0.0])]), &mut input, now);
+        assert_eq!(input.get(InputField::B), 0.0);
+        assert!((input.get(InputField::StickLX) + 0.5).abs() < 1e-6);
+
+        mapper.apply(&frame(&[(BONE_ROOT, [-0.2, -0.1, 0.0])]), &mut input, now);
+        assert_eq!(input.get(InputField::B), 1.0);
+        assert!((input.get(InputField::StickLX) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn velocity() {
+        let speed = rule(Quantity::Velocity { axis: None }, InputField::A, 4.5);
+        let rising = rule(Quantity::Velocity { axis: Some(Axis::Y) }, InputField::B, 3.5);
+        let rising_fast = rule(Quantity::Velocity { axis: Some(Axis::Y) }, InputField::Y, 4.5);
+        let mut mapper = Mapper::new(vec![speed, rising, rising_fast]);
+        let mut input = Input::new();
+        let now = Instant::now();
+
+        // nothing to compare the first frame with
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0; 3])]), &mut input, now);
+        assert_eq!(input.get(InputField::A), 0.0);
+
+        // 0.5m in 100ms, 0.4m of it upwards
+        mapper.apply(&frame(&[(BONE_ROOT, [0.3, 0.4, 0.0])]), &mut input, now + Duration::from_millis(100));
+        assert_eq!(input.get(InputField::A), 1.0);
+        assert_eq!(input.get(InputField::B), 1.0);
+        assert_eq!(input.get(InputField::Y), 0.0);
+
+        // standing still again
+        mapper.apply(&frame(&[(BONE_ROOT, [0.3, 0.4, 0.0])]), &mut input, now + Duration::from_millis(200));
+        assert_eq!(input.get(InputField::A), 0.0);
+        assert_eq!(input.get(InputField::B), 0.0);
+    }
+
+    #[test]
+    fn stick_is_scaled_from_the_threshold() {
+        let walk = Rule { hysteresis: 0.01, range: 0.3, ..rule(Quantity::PositionDelta { axis: Axis::X }, InputField::StickLX, 0.03) };
+        let mut mapper = Mapper::new(vec![walk]);
+        let mut input = Input::new();
+        let now = Instant::now();
+
+        mapper.apply(&frame(&[(BONE_ROOT, [0.0; 3])]), &mut input, now);
+        let mut at = |x: f32| {
+            mapper.apply(&frame(&[(BONE_ROOT, [x, 0.0, 0.0])]), &mut input, now);
+            input.get(InputField::StickLX)
+        };
+        assert_eq!(at(0.02), 0.0);
+        // just past the threshold the stick is barely out of the centre
+        assert!(at(0.031) < 0.01);
+        assert!((at(0.165) - 0.5).abs() < 1e-6);
+        assert!((at(-0.165) + 0.5).abs() < 1e-6);
+        assert_eq!(at(0.3), 1.0);
+        assert_eq!(at(0.5), 1.0);
+        assert_eq!(at(-0.5), -1.0);
+        // still on inside the hysteresis, but centred
+        assert_eq!(at(0.025), 0.0);
+    }
+}
@@ -0,0 +1,385 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+// size of the MCU payload at the end of a 0x31 report, the last byte is a CRC
+pub const MCU_DATA_SIZE: usize = 313;
+// NTAG215 user memory as dumped by common amiibo tools
+pub const AMIIBO_SIZE: usize = 540;
+// tag bytes carried by the first page of a read
+const FIRST_PAGE_SIZE: usize = 245;
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct AmiiboConfig {
+    // directory with .bin dumps
+    pub dir: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum McuState {
+    Suspended,
+    Standby,
+    Nfc,
+}
+
+impl McuState {
+    fn byte(&self) -> u8 {
+        match self {
+            Self::Suspended => 0x00,
+            Self::Standby => 0x01,
+            Self::Nfc => 0x04,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NfcState {
+    Idle,
+    Polling,
+    // number of the next page to send
+    Reading(u8),
+    ReadDone,
+}
+
+#[derive(Clone)]
+pub struct Amiibo {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl Amiibo {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut data = fs::read(path)?;
+        if data.len() < AMIIBO_SIZE {
+            return Err(format!("{} is not an amiibo dump", path.display()).into());
+        }
+        data.truncate(AMIIBO_SIZE);
+
+        Ok(Self {
+            name: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            data,
+        })
+    }
+
+    // 7 byte UID, page 0 carries a check byte after the first three bytes
+    pub fn uid(&self) -> [u8; 7] {
+        let d = &self.data;
+        [d[0], d[1], d[2], d[4], d[5], d[6], d[7]]
+    }
+}
+
+// every .bin file in the directory, sorted by name
+pub fn list_amiibo(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|e| e == "bin").unwrap_or(false))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+pub struct Mcu {
+    pub state: McuState,
+    pub nfc: NfcState,
+    tag: Option<Amiibo>,
+    taps: u32,
+    // answer to the last request, repeated until the next one
+    response: [u8; MCU_DATA_SIZE],
+}
+
+impl Mcu {
+    pub fn new() -> Self {
+        let mut mcu = Self {
+            state: McuState::Suspended,
+            nfc: NfcState::Idle,
+            tag: None,
+            taps: 0,
+            response: [0u8; MCU_DATA_SIZE],
+        };
+        mcu.response = mcu.empty();
+        mcu
+    }
+
+    // place the amiibo on the reader, returns an id for `remove`
+    pub fn tap(&mut self, amiibo: Amiibo) -> u32 {
+        println!("amiibo {} placed", amiibo.name);
+        self.tag = Some(amiibo);
+        self.taps += 1;
+        self.taps
+    }
+
+    // take the amiibo off again unless another one has been placed since
+    pub fn remove(&mut self, tap: u32) {
+        if tap != self.taps {
+            return;
+        }
+        if let Some(a) = self.tag.take() {
+            println!("amiibo {} removed", a.name);
+        }
+        if matches!(self.nfc, NfcState::Reading(_) | NfcState::ReadDone) {
+            self.nfc = NfcState::Polling;
+        }
+    }
+
+    // subcommand 0x20, the tag stays on the reader
+    pub fn reset(&mut self) {
+        self.state = McuState::Suspended;
+        self.nfc = NfcState::Idle;
+        self.response = self.empty();
+    }
+
+    // subcommand 0x22
+    pub fn set_state(&mut self, arg: u8) {
+        self.state = match arg {
+            0x00 => McuState::Suspended,
+            _ => McuState::Standby,
+        };
+        self.nfc = NfcState::Idle;
+        self.response = self.status();
+    }
+
+    // subcommand 0x21, returns the 34 bytes of the reply
+    pub fn configure(&mut self, data: &[u8]) -> [u8; 34] {
+        // 0x21 0x00 <mode>, mode 0x04 is NFC
+        if data.len() >= 3 && data[0] == 0x21 {
+            self.state = match data[2] {
+                0x04 => McuState::Nfc,
+                0x00 => McuState::Suspended,
+                _ => McuState::Standby,
+            };
+        }
+
+        let mut reply = [0u8; 34];
+        reply[..8].copy_from_slice(&[0x01, 0x00, 0xff, 0x00, 0x08, 0x00, 0x1b, self.state.byte()]);
+        reply[33] = crc8(&reply[..33]);
+        reply
+    }
+
+    // output report 0x11, `data` starts at the MCU command
+    pub fn request(&mut self, data: &[u8]) {
+        match data.first() {
+            Some(0x01) => {
+                self.response = self.status();
+            }
+            Some(0x02) => {
+                match data.get(1) {
+                    // cancel, stop polling
+                    Some(0x00) | Some(0x02) => self.nfc = NfcState::Idle,
+                    Some(0x01) => self.nfc = NfcState::Polling,
+                    Some(0x06) => {
+                        if self.tag.is_some() {
+                            self.nfc = NfcState::Reading(1);
+                        }
+                    }
+                    Some(0x04) => {}
+                    _ => println!("MCU unknown NFC command {:02X?}", data.get(1)),
+                }
+                self.response = self.nfc_status();
+            }
+            _ => {
+                println!("MCU unknown request {:02X?}", data.first());
+            }
+        }
+    }
+
+    // MCU payload for the next 0x31 report
+    pub fn report(&mut self) -> [u8; MCU_DATA_SIZE] {
+        let out = match (self.nfc, &self.tag) {
+            (NfcState::Reading(page), Some(tag)) => {
+                let out = Self::read_page(page, tag);
+                self.nfc = if page == 1 { NfcState::Reading(2) } else { NfcState::ReadDone };
+                out
+            }
+            (NfcState::Polling, _) | (NfcState::ReadDone, _) => self.nfc_status(),
+            _ => self.response,
+        };
+
+        Self::finish(out)
+    }
+
+    fn empty(&self) -> [u8; MCU_DATA_SIZE] {
+        let mut out = [0u8; MCU_DATA_SIZE];
+        out[0] = 0xff;
+        Self::finish(out)
+    }
+
+    fn status(&self) -> [u8; MCU_DATA_SIZE] {
+        let mut out = [0u8; MCU_DATA_SIZE];
+        out[..8].copy_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x1b, self.state.byte()]);
+        Self::finish(out)
+    }
+
+    fn nfc_status(&self) -> [u8; MCU_DATA_SIZE] {
+        let mut out = [0u8; MCU_DATA_SIZE];
+        let state = match (self.nfc, &self.tag) {
+            (NfcState::ReadDone, Some(_)) => 0x04,
+            (NfcState::Polling, Some(_)) => 0x09,
+            (NfcState::Polling, None) => 0x01,
+            _ => 0x00,
+        };
+        out[..8].copy_from_slice(&[0x2a, 0x00, 0x05, 0x00, 0x00, 0x09, 0x31, state]);
+
+        if let (Some(tag), 0x09 | 0x04) = (&self.tag, state) {
+            out[8..16].copy_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x01, 0x02, 0x00, 0x07]);
+            out[16..23].copy_from_slice(&tag.uid());
+        }
+
+        Self::finish(out)
+    }
+
+    fn read_page(page: u8, tag: &Amiibo) -> [u8; MCU_DATA_SIZE] {
+        let mut out = [0u8; MCU_DATA_SIZE];
+        if page == 1 {
+            out[..15].copy_from_slice(&[
+                0x3a, 0x00, 0x07, 0x01, 0x00, 0x01, 0x31, 0x02, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x07,
+            ]);
+            out[15..22].copy_from_slice(&tag.uid());
+            out[67..67 + FIRST_PAGE_SIZE].copy_from_slice(&tag.data[..FIRST_PAGE_SIZE]);
+        } else {
+            let rest = &tag.data[FIRST_PAGE_SIZE..];
+            out[..7].copy_from_slice(&[0x3a, 0x00, 0x07, 0x02, 0x00, 0x08, 0x00]);
+            out[7..7 + rest.len()].copy_from_slice(rest);
+        }
+        out
+    }
+
+    fn finish(mut out: [u8; MCU_DATA_SIZE]) -> [u8; MCU_DATA_SIZE] {
+        out[MCU_DATA_SIZE - 1] = crc8(&out[..MCU_DATA_SIZE - 1]);
+        out
+    }
+}
+
+// CRC-8 with polynomial 0x07 as used by the MCU
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amiibo() -> Amiibo {
+        // UID 04 A1 B2 (check byte) C3 D4 E5 F6, then counting bytes
+        let mut data: Vec<u8> = (0..AMIIBO_SIZE).map(|i| i as u8).collect();
+        data[..8].copy_from_slice(&[0x04, 0xa1, 0xb2, 0x97, 0xc3, 0xd4, 0xe5, 0xf6]);
+        Amiibo { name: "test".to_string(), data }
+    }
+
+    const UID: [u8; 7] = [0x04, 0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6];
+
+    fn assert_crc(out: &[u8; MCU_DATA_SIZE]) {
+        assert_eq!(out[MCU_DATA_SIZE - 1], crc8(&out[..MCU_DATA_SIZE - 1]));
+    }
+
+    // the MCU in NFC mode the way the console sets it up
+    fn nfc_mcu() -> Mcu {
+        let mut mcu = Mcu::new();
+        mcu.set_state(0x01);
+        assert_eq!(mcu.state, McuState::Standby);
+        let reply = mcu.configure(&[0x21, 0x00, 0x04]);
+        assert_eq!(mcu.state, McuState::Nfc);
+        assert_eq!(reply[..8], [0x01, 0x00, 0xff, 0x00, 0x08, 0x00, 0x1b, 0x04]);
+        assert_eq!(reply[33], crc8(&reply[..33]));
+        mcu
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        // CRC-8 with polynomial 0x07, no reflection, initial value 0
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc8(&[]), 0x00);
+        assert_eq!(crc8(&[0x01]), 0x07);
+    }
+
+    #[test]
+    fn state_changes() {
+        let mut mcu = Mcu::new();
+        assert_eq!(mcu.report()[0], 0xff);
+
+        mcu.set_state(0x01);
+        mcu.request(&[0x01]);
+        let status = mcu.report();
+        assert_eq!(status[..8], [0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x1b, 0x01]);
+        assert_crc(&status);
+
+        mcu.configure(&[0x21, 0x00, 0x04]);
+        mcu.request(&[0x01]);
+        assert_eq!(mcu.report()[7], 0x04);
+
+        mcu.set_state(0x00);
+        assert_eq!(mcu.state, McuState::Suspended);
+        assert_eq!(mcu.report()[7], 0x00);
+    }
+
+    #[test]
+    fn tap_is_read() {
+        let mut mcu = nfc_mcu();
+        let tag = amiibo();
+
+        // polling without a tag
+        mcu.request(&[0x02, 0x01]);
+        assert_eq!(mcu.nfc, NfcState::Polling);
+        let out = mcu.report();
+        assert_eq!(out[..8], [0x2a, 0x00, 0x05, 0x00, 0x00, 0x09, 0x31, 0x01]);
+        assert_crc(&out);
+
+        // a read before there is a tag does nothing
+        mcu.request(&[0x02, 0x06]);
+        assert_eq!(mcu.nfc, NfcState::Polling);
+
+        let tap = mcu.tap(tag.clone());
+        let out = mcu.report();
+        assert_eq!(out[7], 0x09);
+        assert_eq!(out[16..23], UID);
+        assert_crc(&out);
+
+        mcu.request(&[0x02, 0x06]);
+        assert_eq!(mcu.nfc, NfcState::Reading(1));
+        let page = mcu.report();
+        assert_eq!(page[..7], [0x3a, 0x00, 0x07, 0x01, 0x00, 0x01, 0x31]);
+        assert_eq!(page[15..22], UID);
+        assert_eq!(page[67..67 + FIRST_PAGE_SIZE], tag.data[..FIRST_PAGE_SIZE]);
+        assert_crc(&page);
+
+        let page = mcu.report();
+        assert_eq!(page[..7], [0x3a, 0x00, 0x07, 0x02, 0x00, 0x08, 0x00]);
+        assert_eq!(page[7..7 + AMIIBO_SIZE - FIRST_PAGE_SIZE], tag.data[FIRST_PAGE_SIZE..]);
+        assert!(page[7 + AMIIBO_SIZE - FIRST_PAGE_SIZE..MCU_DATA_SIZE - 1].iter().all(|b| *b == 0));
+        assert_crc(&page);
+
+        assert_eq!(mcu.nfc, NfcState::ReadDone);
+        let out = mcu.report();
+        assert_eq!(out[7], 0x04);
+        assert_eq!(out[16..23], UID);
+
+        // taking it off goes back to polling for the next one
+        mcu.remove(tap);
+        assert_eq!(mcu.nfc, NfcState::Polling);
+        assert_eq!(mcu.report()[7], 0x01);
+
+        mcu.request(&[0x02, 0x02]);
+        assert_eq!(mcu.nfc, NfcState::Idle);
+    }
+
+    #[test]
+    fn remove_after_another_tap() {
+        let mut mcu = nfc_mcu();
+        mcu.request(&[0x02, 0x01]);
+        let first = mcu.tap(amiibo());
+        let second = mcu.tap(amiibo());
+        mcu.remove(first);
+        assert_eq!(mcu.report()[7], 0x09);
+        mcu.remove(second);
+        assert_eq!(mcu.report()[7], 0x01);
+    }
+}
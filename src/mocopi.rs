@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::fs::File;
+use std::time::Instant;
+use crossbeam_channel::Sender;
+use csv::Writer;
+use mocopi_parser::{FramePacket, SkeletonOrFrame};
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use crate::lifecycle::Shutdown;
+
+pub const BONE_COUNT: usize = 27;
+
+// parent of every bone in the mocopi skeleton, the root has none
+const PARENTS: [Option<u16>; BONE_COUNT] = [
+    None,
+    Some(0), Some(1), Some(2), Some(3), Some(4), Some(5), Some(6),
+    Some(7), Some(8), Some(9),
+    Some(7), Some(11), Some(12), Some(13),
+    Some(7), Some(15), Some(16), Some(17),
+    Some(0), Some(19), Some(20), Some(21),
+    Some(0), Some(23), Some(24), Some(25),
+];
+
+pub fn parent(id: u16) -> Option<u16> {
+    PARENTS.get(usize::from(id)).copied().flatten()
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    id: &'a str,
+    time: &'a str,
+    rot_x: f32,
+    rot_y: f32,
+    rot_z: f32,
+    rot_w: f32,
+    pos_x: f32,
+    pos_y: f32,
+    pos_z: f32,
+}
+
+// receive mocopi packets and publish every frame to the mapping stage
+pub fn start_receiver(
+    socket: UdpSocket,
+    sender: Sender<FramePacket>,
+    mut csv: Option<Writer<File>>,
+    shutdown: Shutdown,
+) {
+    tokio::task::spawn(async move {
+        println!("start mocopi receiving");
+
+        let start = Instant::now();
+        let mut buf = [0u8; 2048];
+
+        loop {
+            let received = tokio::select! {
+                r = socket.recv(&mut buf) => r,
+                _ = shutdown.stopped() => break,
+            };
+            let n = match received {
+                Ok(n) => n,
+                Err(e) => {
+                    println!("mocopi receive error: {}", e);
+                    continue;
+                }
+            };
+
+            match mocopi_parser::parse(&mut buf[..n]) {
+                Ok(SkeletonOrFrame::Skeleton(s)) => {
+                    println!("mocopi skeleton: {} bones", s.skeleton.bones.len());
+                }
+                Ok(SkeletonOrFrame::Frame(f)) => {
+                    if let Some(w) = csv.as_mut() {
+                        if let Err(e) = write_rows(w, &f, start) {
+                            println!("mocopi csv error: {}", e);
+                        }
+                    }
+
+                    if sender.send(f).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    println!("mocopi parse error");
+                }
+            }
+        }
+
+        if let Some(Err(e)) = csv.as_mut().map(|w| w.flush()) {
+            println!("mocopi csv error: {}", e);
+        }
+        println!("end mocopi receiving");
+    });
+}
+
+fn write_rows(wtr: &mut Writer<File>, f: &FramePacket, start: Instant) -> Result<(), Box<dyn Error>> {
+    let end = start.elapsed();
+    let time = format!("{}.{:03}", end.as_secs(), end.subsec_millis());
+
+    for b in &f.frame.bones {
+        wtr.serialize(Row {
+            id: b.id.to_string().as_str(),
+            time: time.as_str(),
+            rot_x: b.trans.rot.x,
+            rot_y: b.trans.rot.y,
+            rot_z: b.trans.rot.z,
+            rot_w: b.trans.rot.w,
+            pos_x: b.trans.pos.x,
+            pos_y: b.trans.pos.y,
+            pos_z: b.trans.pos.z,
+        })?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+// the controller's timer byte advances once per tick
+const TICK: Duration = Duration::from_millis(5);
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct PacingConfig {
+    // time between input reports, 8 over USB, 15 to behave like Bluetooth
+    pub interval_ms: u64,
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        Self { interval_ms: 8 }
+    }
+}
+
+impl PacingConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.max(1))
+    }
+}
+
+// source of the timer byte in every input report
+#[derive(Clone, Copy)]
+pub struct Clock {
+    start: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn timer(&self) -> u8 {
+        self.timer_at(Instant::now())
+    }
+
+    fn timer_at(&self, now: Instant) -> u8 {
+        (now.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos()) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Jitter {
+    pub reports: u64,
+    // slots that passed without a report because we were too late
+    pub skipped: u64,
+    pub total_late: Duration,
+    pub max_late: Duration,
+}
+
+impl Jitter {
+    fn record(&mut self, late: Duration, skipped: u32) {
+        self.reports += 1;
+        self.skipped += u64::from(skipped);
+        self.total_late += late;
+        self.max_late = self.max_late.max(late);
+    }
+
+    pub fn mean_late(&self) -> Duration {
+        match self.reports {
+            0 => Duration::ZERO,
+            n => self.total_late / n as u32,
+        }
+    }
+}
+
+impl fmt::Display for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} reports, {} slots skipped, late by {:?} on average and {:?} at most",
+            self.reports,
+            self.skipped,
+            self.mean_late(),
+            self.max_late
+        )
+    }
+}
+
+// hands out report slots on a fixed grid so the rate doesn't drift
+pub struct Pacer {
+    interval: Duration,
+    next: Instant,
+    jitter: Arc<Mutex<Jitter>>,
+}
+
+impl Pacer {
+    pub fn new(interval: Duration, jitter: Arc<Mutex<Jitter>>) -> Self {
+        Self {
+            interval,
+            next: Instant::now(),
+            jitter,
+        }
+    }
+
+    // wait for the next slot, slots we missed entirely are skipped rather than sent in a burst
+    pub async fn tick(&mut self) {
+        tokio::time::sleep_until(self.next.into()).await;
+        self.take_slot(Instant::now());
+    }
+
+    fn take_slot(&mut self, now: Instant) {
+        let late = now.saturating_duration_since(self.next);
+        let missed = (late.as_nanos() / self.interval.as_nanos()) as u32;
+        self.jitter.lock().unwrap().record(late - self.interval * missed, missed);
+        self.next += self.interval * (missed + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn timer_wraps() {
+        let start = Instant::now();
+        let clock = Clock { start };
+        assert_eq!(clock.timer_at(start), 0);
+        assert_eq!(clock.timer_at(start + 4 * MS), 0);
+        assert_eq!(clock.timer_at(start + 5 * MS), 1);
+        assert_eq!(clock.timer_at(start + 1279 * MS), 255);
+        assert_eq!(clock.timer_at(start + 1280 * MS), 0);
+        assert_eq!(clock.timer_at(start + 1287 * MS), 1);
+    }
+
+    #[test]
+    fn slots_on_a_grid() {
+        let start = Instant::now();
+        let jitter = Arc::new(Mutex::new(Jitter::default()));
+        let mut pacer = Pacer { interval: 8 * MS, next: start, jitter: Arc::clone(&jitter) };
+
+        // on time, then late within the slot
+        pacer.take_slot(start);
+        pacer.take_slot(start + 11 * MS);
+        // the next slot stays on the grid rather than 8ms after the late one
+        assert_eq!(pacer.next, start + 16 * MS);
+
+        // at 41ms the slots at 16, 24 and 32 have passed, the one at 40 is sent 1ms late
+        pacer.take_slot(start + 41 * MS);
+        assert_eq!(pacer.next, start + 48 * MS);
+
+        let j = *jitter.lock().unwrap();
+        assert_eq!((j.reports, j.skipped), (3, 3));
+        assert_eq!((j.total_late, j.max_late), (4 * MS, 3 * MS));
+    }
+
+    #[test]
+    fn jitter_statistics() {
+        let mut j = Jitter::default();
+        assert_eq!(j.mean_late(), Duration::ZERO);
+        j.record(2 * MS, 0);
+        j.record(Duration::ZERO, 2);
+        j.record(4 * MS, 1);
+        assert_eq!(j.mean_late(), 2 * MS);
+        assert_eq!(j.max_late, 4 * MS);
+        assert_eq!(j.to_string(), "3 reports, 3 slots skipped, late by 2ms on average and 4ms at most");
+    }
+}
@@ -0,0 +1,320 @@
+use std::error::Error;
+use std::time::Duration;
+use crate::capture::{Direction, Record};
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE: u32 = 0x01;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x06;
+const LINKTYPE_USB_LINUX: u16 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+const USB_TRANSFER_INTERRUPT: u8 = 0x01;
+const USB_DIRECTION_IN: u8 = 0x80;
+
+pub fn is_pcap(data: &[u8]) -> bool {
+    match data.get(..4) {
+        Some(m) => {
+            let le = u32::from_le_bytes(m.try_into().unwrap());
+            let be = u32::from_be_bytes(m.try_into().unwrap());
+            [le, be].iter().any(|m| [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS, PCAPNG_SECTION].contains(m))
+        }
+        None => false,
+    }
+}
+
+// HID interrupt transfers of the controller in a usbmon pcap or pcapng file
+pub fn parse(data: &[u8]) -> Result<Vec<Record>, Box<dyn Error>> {
+    let packets = if read_u32(data, 0)? == PCAPNG_SECTION {
+        parse_pcapng(data)?
+    } else {
+        parse_pcap(data)?
+    };
+
+    let mut transfers = vec![];
+    for p in &packets {
+        if let Some(t) = Transfer::parse(p)? {
+            transfers.push(t);
+        }
+    }
+
+    // the console starts with a USB command, follow the device it was sent to
+    let device = transfers
+        .iter()
+        .find(|t| t.direction == Direction::FromHost && t.data.first() == Some(&0x80))
+        .map(|t| (t.bus, t.device))
+        .ok_or("no HID handshake found in the capture")?;
+
+    let start = packets.first().map(|p| p.time).unwrap_or_default();
+    Ok(transfers
+        .into_iter()
+        .filter(|t| (t.bus, t.device) == device)
+        .map(|t| Record {
+            time: t.time.saturating_sub(start),
+            direction: t.direction,
+            data: t.data,
+        })
+        .collect())
+}
+
+struct Packet<'a> {
+    time: Duration,
+    linktype: u16,
+    data: &'a [u8],
+}
+
+// capture files are written in the byte order of the capturing machine, so is the usbmon
+// header inside them, and only little endian machines are supported
+const BIG_ENDIAN: &str = "big endian captures are not supported";
+
+fn parse_pcap(data: &[u8]) -> Result<Vec<Packet<'_>>, Box<dyn Error>> {
+    let nanos = match read_u32(data, 0)? {
+        PCAP_MAGIC_MICROS => false,
+        PCAP_MAGIC_NANOS => true,
+        m if [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS].contains(&m.swap_bytes()) => return Err(BIG_ENDIAN.into()),
+        _ => return Err("not a pcap file".into()),
+    };
+    let linktype = usbmon(read_u32(data, 20)? as u16)?;
+
+    let mut packets = vec![];
+    let mut offset = 24;
+    while offset < data.len() {
+        let secs = read_u32(data, offset)?;
+        let frac = read_u32(data, offset + 4)?;
+        let len = read_u32(data, offset + 8)? as usize;
+        let body = slice(data, offset + 16, len)?;
+
+        let frac = if nanos { Duration::from_nanos(frac.into()) } else { Duration::from_micros(frac.into()) };
+        packets.push(Packet {
+            time: Duration::from_secs(secs.into()) + frac,
+            linktype,
+            data: body,
+        });
+        offset += 16 + len;
+    }
+
+    Ok(packets)
+}
+
+fn parse_pcapng(data: &[u8]) -> Result<Vec<Packet<'_>>, Box<dyn Error>> {
+    let mut packets = vec![];
+    // link type and timestamp units per second per interface of the current section
+    let mut interfaces: Vec<(u16, u64)> = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let block_type = read_u32(data, offset)?;
+        if block_type == PCAPNG_SECTION {
+            match read_u32(data, offset + 8)? {
+                PCAPNG_BYTE_ORDER => {}
+                m if m.swap_bytes() == PCAPNG_BYTE_ORDER => return Err(BIG_ENDIAN.into()),
+                _ => return Err("invalid pcapng byte order".into()),
+            }
+            interfaces.clear();
+        }
+
+        let len = read_u32(data, offset + 4)? as usize;
+        if len < 12 {
+            return Err(format!("invalid pcapng block at {:#X}", offset).into());
+        }
+        let block = slice(data, offset, len)?;
+
+        match block_type {
+            PCAPNG_INTERFACE => {
+                let linktype = usbmon(read_u16(block, 8)?)?;
+                interfaces.push((linktype, timestamp_resolution(block)?));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = read_u32(block, 8)? as usize;
+                let (linktype, units) = *interfaces
+                    .get(interface)
+                    .ok_or_else(|| format!("unknown pcapng interface {}", interface))?;
+                let ts = (u64::from(read_u32(block, 12)?) << 32) | u64::from(read_u32(block, 16)?);
+                let captured = read_u32(block, 20)? as usize;
+
+                packets.push(Packet {
+                    time: Duration::from_nanos((u128::from(ts) * 1_000_000_000 / u128::from(units)) as u64),
+                    linktype,
+                    data: slice(block, 28, captured)?,
+                });
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    Ok(packets)
+}
+
+// timestamp units per second from the if_tsresol option, microseconds by default
+fn timestamp_resolution(block: &[u8]) -> Result<u64, Box<dyn Error>> {
+    let mut offset = 16;
+    while offset + 4 <= block.len() - 4 {
+        let code = read_u16(block, offset)?;
+        let len = usize::from(read_u16(block, offset + 2)?);
+        if code == 0 {
+            break;
+        }
+        if code == 9 && len == 1 {
+            let v = block[offset + 4];
+            let units = if v & 0x80 == 0 { 10u64.checked_pow(v.into()) } else { 2u64.checked_pow((v & 0x7f).into()) };
+            return units.ok_or_else(|| format!("unsupported pcapng timestamp resolution {:02X}", v).into());
+        }
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    Ok(1_000_000)
+}
+
+fn usbmon(linktype: u16) -> Result<u16, Box<dyn Error>> {
+    match linktype {
+        LINKTYPE_USB_LINUX | LINKTYPE_USB_LINUX_MMAPPED => Ok(linktype),
+        t => Err(format!("link type {} is not a usbmon capture", t).into()),
+    }
+}
+
+struct Transfer {
+    time: Duration,
+    bus: u16,
+    device: u8,
+    direction: Direction,
+    data: Vec<u8>,
+}
+
+impl Transfer {
+    // the link type is checked when the capture is read
+    fn parse(packet: &Packet) -> Result<Option<Self>, Box<dyn Error>> {
+        let header_size = match packet.linktype {
+            LINKTYPE_USB_LINUX_MMAPPED => 64,
+            _ => 48,
+        };
+        let d = packet.data;
+        if d.len() < header_size {
+            return Ok(None);
+        }
+
+        let event = d[8];
+        let transfer_type = d[9];
+        let endpoint = d[10];
+        let data = &d[header_size..];
+        if transfer_type != USB_TRANSFER_INTERRUPT || data.is_empty() {
+            return Ok(None);
+        }
+
+        // OUT data is captured on submission, IN data on completion
+        let direction = match (event, endpoint & USB_DIRECTION_IN) {
+            (b'S', 0) => Direction::FromHost,
+            (b'C', USB_DIRECTION_IN) => Direction::ToHost,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            time: packet.time,
+            bus: read_u16(d, 12)?,
+            device: d[11],
+            direction,
+            data: data.to_vec(),
+        }))
+    }
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], Box<dyn Error>> {
+    data.get(offset..offset + len).ok_or_else(|| "capture is truncated".into())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Box<dyn Error>> {
+    Ok(u16::from_le_bytes(slice(data, offset, 2)?.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Box<dyn Error>> {
+    Ok(u32::from_le_bytes(slice(data, offset, 4)?.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::capture::{self, Exchange};
+    use crate::config::Config;
+
+    // the emulator's session with the simulated host written out like usbmon sees it on a Linux
+    // host: submissions and completions on endpoints 0x01 and 0x81 of device 5, the pcap with
+    // mmapped headers in microseconds, the pcapng with plain headers in nanoseconds and a mouse
+    // on the same bus
+    const PCAP: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/handshake.pcap");
+    const PCAPNG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/handshake.pcapng");
+
+    fn check_exchanges(exchanges: &[Exchange]) {
+        // status, handshake, high speed, handshake and 14 subcommands
+        assert_eq!(exchanges.len(), 18);
+        for e in exchanges {
+            let reply = e.reply.as_deref().unwrap_or_else(|| panic!("no reply to {:02X?}", &e.request[..11]));
+            match e.request[0] {
+                0x80 => assert_eq!(reply[..2], [0x81, e.request[1]]),
+                0x01 => {
+                    assert_eq!(reply[0], 0x21);
+                    assert_eq!(reply[14], e.request[10]);
+                    // SPI reads come back with their address and length
+                    if e.request[10] == 0x10 {
+                        assert_eq!(reply[15..20], e.request[11..16]);
+                    }
+                }
+                id => panic!("request {:02X}", id),
+            }
+        }
+    }
+
+    #[test]
+    fn usbmon_captures_load() {
+        for path in [PCAP, PCAPNG] {
+            let records = capture::load(path).unwrap();
+            // the mouse and the empty submissions and completions are left out
+            assert_eq!(records.len(), 45, "{}", path);
+            assert_eq!(records[0].direction, Direction::ToHost);
+            assert_eq!(records[0].data[..2], [0x81, 0x03]);
+            // from the first packet, the submission 900µs before the announcement
+            assert_eq!(records[0].time, Duration::from_micros(900));
+            assert_eq!(records[2].data[..2], [0x80, 0x01]);
+            assert_eq!(records[2].time - records[0].time, Duration::from_micros(2709 - 1506));
+            check_exchanges(&capture::exchanges(&records));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn replay_matches() {
+        for path in [PCAP, PCAPNG] {
+            let mismatches = crate::replay(path, &Config::default()).await.unwrap();
+            assert!(mismatches.is_empty(), "{}: {:?}", path, mismatches);
+        }
+    }
+
+    fn pcap_header(magic: [u8; 4], linktype: u32) -> Vec<u8> {
+        let mut header = magic.to_vec();
+        header.extend([0x02, 0x00, 0x04, 0x00]);
+        header.extend([0x00; 12]);
+        header.extend(linktype.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn unsupported_captures() {
+        let error = |data: &[u8]| parse(data).err().map(|e| e.to_string()).unwrap_or_default();
+        assert_eq!(error(&pcap_header([0xa1, 0xb2, 0xc3, 0xd4], 220)), BIG_ENDIAN);
+        assert_eq!(error(&pcap_header([0xd4, 0xc3, 0xb2, 0xa1], 1)), "link type 1 is not a usbmon capture");
+        assert_eq!(error(&pcap_header([0xd4, 0xc3, 0xb2, 0xa1], 220)), "no HID handshake found in the capture");
+
+        let mut pcapng = fs::read(PCAPNG).unwrap();
+        pcapng[8..12].reverse();
+        assert_eq!(error(&pcapng), BIG_ENDIAN);
+
+        let mut pcapng = fs::read(PCAPNG).unwrap();
+        // link type of the interface description after the 28 byte section header
+        pcapng[36] = 0x01;
+        pcapng[37] = 0x00;
+        assert_eq!(error(&pcapng), "link type 1 is not a usbmon capture");
+
+        let pcap = fs::read(PCAP).unwrap();
+        assert_eq!(error(&pcap[..pcap.len() - 10]), "capture is truncated");
+    }
+}
@@ -0,0 +1,678 @@
+use std::error::Error;
+use std::fmt;
+use crate::mcu::MCU_DATA_SIZE;
+
+// every report on the wire is padded to at least this size
+pub const REPORT_SIZE: usize = 64;
+// buttons, sticks and the vibrator byte at the start of 0x21, 0x30 and 0x31
+pub const INPUT_SIZE: usize = 11;
+pub const IMU_SIZE: usize = 36;
+pub const RUMBLE_SIZE: usize = 8;
+// what fits behind the ack and subcommand id of a 0x21 reply
+pub const MAX_REPLY_DATA: usize = REPORT_SIZE - 2 - INPUT_SIZE - 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    Empty,
+    Truncated { id: u8, len: usize, need: usize },
+    UnknownReport(u8),
+    PayloadTooLarge { id: u8, len: usize, max: usize },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty report"),
+            Self::Truncated { id, len, need } => {
+                write!(f, "report {:02X} is {} bytes, needs {}", id, len, need)
+            }
+            Self::UnknownReport(id) => write!(f, "unknown report {:02X}", id),
+            Self::PayloadTooLarge { id, len, max } => {
+                write!(f, "payload of {:02X} is {} bytes, at most {} fit", id, len, max)
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+fn need(buf: &[u8], len: usize) -> Result<(), ProtocolError> {
+    if buf.len() < len {
+        return Err(ProtocolError::Truncated { id: buf[0], len: buf.len(), need: len });
+    }
+    Ok(())
+}
+
+fn u32_le(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+// 0x80 commands, only sent over USB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbCommand {
+    Status,
+    Handshake,
+    HighSpeed,
+    // stop the Bluetooth timeout and talk over USB only
+    ForceUsb,
+    DisableForceUsb,
+    Unknown(u8),
+}
+
+impl UsbCommand {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Status => 0x01,
+            Self::Handshake => 0x02,
+            Self::HighSpeed => 0x03,
+            Self::ForceUsb => 0x04,
+            Self::DisableForceUsb => 0x05,
+            Self::Unknown(id) => *id,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            0x01 => Self::Status,
+            0x02 => Self::Handshake,
+            0x03 => Self::HighSpeed,
+            0x04 => Self::ForceUsb,
+            0x05 => Self::DisableForceUsb,
+            _ => Self::Unknown(id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Subcommand {
+    GetState,
+    BluetoothPairing(Vec<u8>),
+    DeviceInfo,
+    SetReportMode(u8),
+    TriggerElapsed,
+    PageListState,
+    SetHciState(u8),
+    ResetPairing,
+    SetShipmentMode(u8),
+    SpiRead { addr: u32, len: u8 },
+    SpiWrite { addr: u32, data: Vec<u8> },
+    SpiErase { addr: u32 },
+    McuReset,
+    McuConfig(Vec<u8>),
+    McuState(u8),
+    SetPlayerLights(u8),
+    GetPlayerLights,
+    SetHomeLight(Vec<u8>),
+    EnableImu(u8),
+    ImuSensitivity { gyro: u8, accel: u8, gyro_rate: u8, accel_filter: u8 },
+    WriteImuRegister { addr: u8, value: u8 },
+    ReadImuRegister { addr: u8, len: u8 },
+    EnableVibration(u8),
+    GetVoltage,
+    Unknown { id: u8, data: Vec<u8> },
+}
+
+impl Subcommand {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::GetState => 0x00,
+            Self::BluetoothPairing(_) => 0x01,
+            Self::DeviceInfo => 0x02,
+            Self::SetReportMode(_) => 0x03,
+            Self::TriggerElapsed => 0x04,
+            Self::PageListState => 0x05,
+            Self::SetHciState(_) => 0x06,
+            Self::ResetPairing => 0x07,
+            Self::SetShipmentMode(_) => 0x08,
+            Self::SpiRead { .. } => 0x10,
+            Self::SpiWrite { .. } => 0x11,
+            Self::SpiErase { .. } => 0x12,
+            Self::McuReset => 0x20,
+            Self::McuConfig(_) => 0x21,
+            Self::McuState(_) => 0x22,
+            Self::SetPlayerLights(_) => 0x30,
+            Self::GetPlayerLights => 0x31,
+            Self::SetHomeLight(_) => 0x38,
+            Self::EnableImu(_) => 0x40,
+            Self::ImuSensitivity { .. } => 0x41,
+            Self::WriteImuRegister { .. } => 0x42,
+            Self::ReadImuRegister { .. } => 0x43,
+            Self::EnableVibration(_) => 0x48,
+            Self::GetVoltage => 0x50,
+            Self::Unknown { id, .. } => *id,
+        }
+    }
+
+    // `buf` starts at the subcommand id
+    pub fn parse(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let id = *buf.first().ok_or(ProtocolError::Empty)?;
+        let args = &buf[1..];
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+
+        let sub = match id {
+            0x00 => Self::GetState,
+            0x01 => Self::BluetoothPairing(args.to_vec()),
+            0x02 => Self::DeviceInfo,
+            0x03 => Self::SetReportMode(arg(0)),
+            0x04 => Self::TriggerElapsed,
+            0x05 => Self::PageListState,
+            0x06 => Self::SetHciState(arg(0)),
+            0x07 => Self::ResetPairing,
+            0x08 => Self::SetShipmentMode(arg(0)),
+            0x10 => {
+                need(buf, 6)?;
+                Self::SpiRead { addr: u32_le(args), len: args[4] }
+            }
+            0x11 => {
+                need(buf, 6)?;
+                let len = usize::from(args[4]);
+                need(buf, 6 + len)?;
+                Self::SpiWrite { addr: u32_le(args), data: args[5..5 + len].to_vec() }
+            }
+            0x12 => {
+                need(buf, 5)?;
+                Self::SpiErase { addr: u32_le(args) }
+            }
+            0x20 => Self::McuReset,
+            0x21 => Self::McuConfig(args.to_vec()),
+            0x22 => Self::McuState(arg(0)),
+            0x30 => Self::SetPlayerLights(arg(0)),
+            0x31 => Self::GetPlayerLights,
+            0x38 => Self::SetHomeLight(args.to_vec()),
+            0x40 => Self::EnableImu(arg(0)),
+            0x41 => Self::ImuSensitivity {
+                gyro: arg(0),
+                accel: arg(1),
+                gyro_rate: arg(2),
+                accel_filter: arg(3),
+            },
+            0x42 => Self::WriteImuRegister { addr: arg(1), value: arg(2) },
+            0x43 => Self::ReadImuRegister { addr: arg(0), len: arg(1) },
+            0x48 => Self::EnableVibration(arg(0)),
+            0x50 => Self::GetVoltage,
+            _ => Self::Unknown { id, data: args.to_vec() },
+        };
+
+        Ok(sub)
+    }
+
+    // the host's side of the protocol
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.id()];
+        match self {
+            Self::BluetoothPairing(data)
+            | Self::McuConfig(data)
+            | Self::SetHomeLight(data)
+            | Self::Unknown { data, .. } => buf.extend(data),
+            Self::SetReportMode(v)
+            | Self::SetHciState(v)
+            | Self::SetShipmentMode(v)
+            | Self::McuState(v)
+            | Self::SetPlayerLights(v)
+            | Self::EnableImu(v)
+            | Self::EnableVibration(v) => buf.push(*v),
+            Self::SpiRead { addr, len } => {
+                buf.extend(addr.to_le_bytes());
+                buf.push(*len);
+            }
+            Self::SpiWrite { addr, data } => {
+                buf.extend(addr.to_le_bytes());
+                buf.push(data.len() as u8);
+                buf.extend(data);
+            }
+            Self::SpiErase { addr } => buf.extend(addr.to_le_bytes()),
+            Self::ImuSensitivity { gyro, accel, gyro_rate, accel_filter } => {
+                buf.extend([*gyro, *accel, *gyro_rate, *accel_filter]);
+            }
+            Self::WriteImuRegister { addr, value } => buf.extend([0x01, *addr, *value]),
+            Self::ReadImuRegister { addr, len } => buf.extend([*addr, *len]),
+            Self::GetState
+            | Self::DeviceInfo
+            | Self::TriggerElapsed
+            | Self::PageListState
+            | Self::ResetPairing
+            | Self::McuReset
+            | Self::GetPlayerLights
+            | Self::GetVoltage => {}
+        }
+        buf
+    }
+}
+
+// host to controller
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutputReport {
+    Usb(UsbCommand),
+    Subcommand { timer: u8, rumble: [u8; RUMBLE_SIZE], subcommand: Subcommand },
+    Rumble { timer: u8, rumble: [u8; RUMBLE_SIZE] },
+    Mcu { timer: u8, rumble: [u8; RUMBLE_SIZE], data: Vec<u8> },
+}
+
+impl OutputReport {
+    pub fn parse(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let id = *buf.first().ok_or(ProtocolError::Empty)?;
+
+        let rumble = |buf: &[u8]| -> Result<[u8; RUMBLE_SIZE], ProtocolError> {
+            need(buf, 2 + RUMBLE_SIZE)?;
+            let mut r = [0u8; RUMBLE_SIZE];
+            r.copy_from_slice(&buf[2..2 + RUMBLE_SIZE]);
+            Ok(r)
+        };
+
+        match id {
+            0x80 => {
+                need(buf, 2)?;
+                Ok(Self::Usb(UsbCommand::from_id(buf[1])))
+            }
+            0x01 => {
+                let rumble = rumble(buf)?;
+                need(buf, 11)?;
+                Ok(Self::Subcommand { timer: buf[1], rumble, subcommand: Subcommand::parse(&buf[10..])? })
+            }
+            0x10 => Ok(Self::Rumble { timer: buf[1], rumble: rumble(buf)? }),
+            0x11 => Ok(Self::Mcu { timer: buf[1], rumble: rumble(buf)?, data: buf[10..].to_vec() }),
+            _ => Err(ProtocolError::UnknownReport(id)),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = match self {
+            Self::Usb(cmd) => vec![0x80, cmd.id()],
+            Self::Subcommand { timer, rumble, subcommand } => {
+                let mut buf = vec![0x01, *timer];
+                buf.extend(rumble);
+                buf.extend(subcommand.encode());
+                buf
+            }
+            Self::Rumble { timer, rumble } => {
+                let mut buf = vec![0x10, *timer];
+                buf.extend(rumble);
+                buf
+            }
+            Self::Mcu { timer, rumble, data } => {
+                let mut buf = vec![0x11, *timer];
+                buf.extend(rumble);
+                buf.extend(data);
+                buf
+            }
+        };
+        buf.resize(buf.len().max(REPORT_SIZE), 0x00);
+        buf
+    }
+}
+
+// ack byte and payload of a 0x21 subcommand reply
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub ack: u8,
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+impl Reply {
+    pub fn ack(id: u8) -> Self {
+        Self { ack: 0x80, id, data: vec![] }
+    }
+
+    pub fn nack(id: u8) -> Self {
+        Self { ack: 0x00, id, data: vec![] }
+    }
+
+    // replies carrying data announce their type in the ack byte
+    pub fn with_data(id: u8, data: Vec<u8>) -> Self {
+        let ack = match id {
+            0x02 => 0x82,
+            0x03 | 0x05 => 0x80,
+            0x04 => 0x83,
+            0x10 => 0x90,
+            0x11 | 0x12 => 0x80,
+            0x21 => 0xa0,
+            0x31 => 0xb0,
+            0x43 => 0xc0,
+            0x50 => 0xd0,
+            _ => 0x80 | id,
+        };
+        Self { ack, id, data }
+    }
+}
+
+// input reports the host can ask for with subcommand 0x03
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportMode {
+    // 0x30, buttons, sticks and IMU
+    Standard,
+    // 0x31, the 0x30 report followed by the NFC/IR MCU payload
+    Nfc,
+    // 0x3f, only sent when something changes on a real controller
+    Simple,
+}
+
+impl ReportMode {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Standard => 0x30,
+            Self::Nfc => 0x31,
+            Self::Simple => 0x3f,
+        }
+    }
+
+    // the IR polling and MCU update modes are not emulated
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x30 => Some(Self::Standard),
+            0x31 => Some(Self::Nfc),
+            0x3f => Some(Self::Simple),
+            _ => None,
+        }
+    }
+}
+
+// controller to host
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputReport {
+    Reply { timer: u8, input: [u8; INPUT_SIZE], reply: Reply },
+    Standard { timer: u8, input: [u8; INPUT_SIZE], imu: [u8; IMU_SIZE] },
+    Nfc { timer: u8, input: [u8; INPUT_SIZE], imu: [u8; IMU_SIZE], mcu: Box<[u8; MCU_DATA_SIZE]> },
+    // buttons, hat switch and 16-bit sticks
+    Simple { buttons: [u8; 2], hat: u8, sticks: [u16; 4] },
+    Usb { command: u8, data: Vec<u8> },
+}
+
+impl InputReport {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Reply { .. } => 0x21,
+            Self::Standard { .. } => 0x30,
+            Self::Nfc { .. } => 0x31,
+            Self::Simple { .. } => 0x3f,
+            Self::Usb { .. } => 0x81,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = vec![self.id()];
+        match self {
+            Self::Reply { timer, input, reply } => {
+                if reply.data.len() > MAX_REPLY_DATA {
+                    return Err(ProtocolError::PayloadTooLarge {
+                        id: reply.id,
+                        len: reply.data.len(),
+                        max: MAX_REPLY_DATA,
+                    });
+                }
+                buf.push(*timer);
+                buf.extend(input);
+                buf.extend([reply.ack, reply.id]);
+                buf.extend(&reply.data);
+            }
+            Self::Standard { timer, input, imu } => {
+                buf.push(*timer);
+                buf.extend(input);
+                buf.extend(imu);
+            }
+            Self::Nfc { timer, input, imu, mcu } => {
+                buf.push(*timer);
+                buf.extend(input);
+                buf.extend(imu);
+                buf.extend(mcu.iter());
+            }
+            Self::Simple { buttons, hat, sticks } => {
+                buf.extend(buttons);
+                buf.push(*hat);
+                for s in sticks {
+                    buf.extend(s.to_le_bytes());
+                }
+            }
+            Self::Usb { command, data } => {
+                if data.len() > REPORT_SIZE - 2 {
+                    return Err(ProtocolError::PayloadTooLarge {
+                        id: *command,
+                        len: data.len(),
+                        max: REPORT_SIZE - 2,
+                    });
+                }
+                buf.push(*command);
+                buf.extend(data);
+            }
+        }
+        buf.resize(buf.len().max(REPORT_SIZE), 0x00);
+        Ok(buf)
+    }
+
+    // the host's side of the protocol
+    pub fn parse(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let id = *buf.first().ok_or(ProtocolError::Empty)?;
+
+        let input = |buf: &[u8]| {
+            let mut input = [0u8; INPUT_SIZE];
+            input.copy_from_slice(&buf[2..2 + INPUT_SIZE]);
+            input
+        };
+        let imu = |buf: &[u8]| {
+            let mut imu = [0u8; IMU_SIZE];
+            imu.copy_from_slice(&buf[2 + INPUT_SIZE..2 + INPUT_SIZE + IMU_SIZE]);
+            imu
+        };
+
+        match id {
+            0x21 => {
+                need(buf, 2 + INPUT_SIZE + 2)?;
+                let reply = Reply {
+                    ack: buf[13],
+                    id: buf[14],
+                    data: buf[15..].to_vec(),
+                };
+                Ok(Self::Reply { timer: buf[1], input: input(buf), reply })
+            }
+            0x30 => {
+                need(buf, 2 + INPUT_SIZE + IMU_SIZE)?;
+                Ok(Self::Standard { timer: buf[1], input: input(buf), imu: imu(buf) })
+            }
+            0x31 => {
+                let start = 2 + INPUT_SIZE + IMU_SIZE;
+                need(buf, start + MCU_DATA_SIZE)?;
+                let mut mcu = Box::new([0u8; MCU_DATA_SIZE]);
+                mcu.copy_from_slice(&buf[start..start + MCU_DATA_SIZE]);
+                Ok(Self::Nfc { timer: buf[1], input: input(buf), imu: imu(buf), mcu })
+            }
+            0x3f => {
+                need(buf, 12)?;
+                let mut sticks = [0u16; 4];
+                for (i, s) in sticks.iter_mut().enumerate() {
+                    *s = u16::from_le_bytes([buf[4 + i * 2], buf[5 + i * 2]]);
+                }
+                Ok(Self::Simple { buttons: [buf[1], buf[2]], hat: buf[3], sticks })
+            }
+            0x81 => {
+                need(buf, 2)?;
+                Ok(Self::Usb { command: buf[1], data: buf[2..].to_vec() })
+            }
+            _ => Err(ProtocolError::UnknownReport(id)),
+        }
+    }
+}
+
+// the reports are written by hand after the documented layouts, none comes from a capture
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // neutral rumble for both sides, as the console sends it
+    const NEUTRAL_RUMBLE: [u8; RUMBLE_SIZE] = [0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40];
+
+    fn padded(bytes: &[u8]) -> Vec<u8> {
+        let mut buf = bytes.to_vec();
+        buf.resize(REPORT_SIZE, 0x00);
+        buf
+    }
+
+    #[test]
+    fn spi_read_request() {
+        // a read of the serial number area, like the console's during the handshake
+        let raw = padded(&[0x01, 0x0c, 0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40, 0x10, 0x00, 0x60, 0x00, 0x00, 0x10]);
+        let report = OutputReport::parse(&raw).unwrap();
+        assert_eq!(
+            report,
+            OutputReport::Subcommand {
+                timer: 0x0c,
+                rumble: NEUTRAL_RUMBLE,
+                subcommand: Subcommand::SpiRead { addr: 0x6000, len: 0x10 },
+            }
+        );
+        assert_eq!(report.encode(), raw);
+    }
+
+    #[test]
+    fn usb_commands() {
+        assert_eq!(OutputReport::parse(&padded(&[0x80, 0x02])).unwrap(), OutputReport::Usb(UsbCommand::Handshake));
+        assert_eq!(OutputReport::parse(&padded(&[0x80, 0x04])).unwrap(), OutputReport::Usb(UsbCommand::ForceUsb));
+
+        // status reply with the controller type and the MAC backwards
+        let raw = padded(&[0x81, 0x01, 0x00, 0x03, 0x00, 0x00, 0x5e, 0x00, 0x53, 0x5e]);
+        let report = InputReport::parse(&raw).unwrap();
+        match &report {
+            InputReport::Usb { command, data } => {
+                assert_eq!(*command, 0x01);
+                assert_eq!(&data[..8], &[0x00, 0x03, 0x00, 0x00, 0x5e, 0x00, 0x53, 0x5e]);
+            }
+            other => panic!("expected a USB reply, got {:?}", other),
+        }
+        assert_eq!(report.encode().unwrap(), raw);
+    }
+
+    #[test]
+    fn spi_read_reply() {
+        // ack 0x90 for subcommand 0x10, echoing the address and length before the data
+        let raw = padded(&[
+            0x21, 0x0a, 0x8e, 0x00, 0x00, 0x00, 0x0f, 0xf8, 0x7f, 0x00, 0x08, 0x80, 0x00, 0x90, 0x10, 0x00, 0x60, 0x00,
+            0x00, 0x10, 0xff, 0xff, 0xff, 0xff,
+        ]);
+        let report = InputReport::parse(&raw).unwrap();
+        match &report {
+            InputReport::Reply { timer, input, reply } => {
+                assert_eq!(*timer, 0x0a);
+                assert_eq!(input[0], 0x8e);
+                assert_eq!((reply.ack, reply.id), (0x90, 0x10));
+                assert_eq!(&reply.data[..5], &[0x00, 0x60, 0x00, 0x00, 0x10]);
+                assert_eq!(reply.data.len(), MAX_REPLY_DATA);
+                assert_eq!(Reply::with_data(0x10, vec![]).ack, reply.ack);
+            }
+            other => panic!("expected a reply, got {:?}", other),
+        }
+        assert_eq!(report.encode().unwrap(), raw);
+    }
+
+    #[test]
+    fn standard_report() {
+        let mut bytes = vec![0x30, 0x5b, 0x8e, 0x00, 0x00, 0x00, 0x0f, 0xf8, 0x7f, 0x00, 0x08, 0x80, 0x00];
+        // three IMU samples, resting flat
+        for _ in 0..3 {
+            bytes.extend([0xf4, 0xff, 0x0e, 0x00, 0xd0, 0x0f, 0x01, 0x00, 0xfe, 0xff, 0x03, 0x00]);
+        }
+        let raw = padded(&bytes);
+
+        let report = InputReport::parse(&raw).unwrap();
+        match &report {
+            InputReport::Standard { timer, input, imu } => {
+                assert_eq!(*timer, 0x5b);
+                assert_eq!(&input[4..7], &[0x0f, 0xf8, 0x7f]);
+                assert_eq!(&imu[..4], &[0xf4, 0xff, 0x0e, 0x00]);
+            }
+            other => panic!("expected a standard report, got {:?}", other),
+        }
+        assert_eq!(report.encode().unwrap(), raw);
+    }
+
+    #[test]
+    fn ack_bytes() {
+        let table = [
+            (0x00, 0x80),
+            (0x02, 0x82),
+            (0x03, 0x80),
+            (0x04, 0x83),
+            (0x05, 0x80),
+            (0x10, 0x90),
+            (0x11, 0x80),
+            (0x12, 0x80),
+            (0x21, 0xa0),
+            (0x30, 0xb0),
+            (0x31, 0xb0),
+            (0x43, 0xc0),
+            (0x48, 0xc8),
+            (0x50, 0xd0),
+        ];
+        for (id, ack) in table {
+            assert_eq!(Reply::with_data(id, vec![]).ack, ack, "subcommand {:02X}", id);
+        }
+        assert_eq!(Reply::ack(0x48).ack, 0x80);
+        assert_eq!(Reply::nack(0x48).ack, 0x00);
+    }
+
+    #[test]
+    fn subcommands_round_trip() {
+        let subcommands = [
+            Subcommand::GetState,
+            Subcommand::BluetoothPairing(vec![0x01, 0x02]),
+            Subcommand::DeviceInfo,
+            Subcommand::SetReportMode(0x30),
+            Subcommand::TriggerElapsed,
+            Subcommand::PageListState,
+            Subcommand::SetHciState(0x01),
+            Subcommand::ResetPairing,
+            Subcommand::SetShipmentMode(0x00),
+            Subcommand::SpiRead { addr: 0x8010, len: 0x16 },
+            Subcommand::SpiWrite { addr: 0x8010, data: vec![0xb2, 0xa1, 0x00] },
+            Subcommand::SpiErase { addr: 0x8000 },
+            Subcommand::McuReset,
+            Subcommand::McuConfig(vec![0x21, 0x00, 0x04]),
+            Subcommand::McuState(0x01),
+            Subcommand::SetPlayerLights(0x01),
+            Subcommand::GetPlayerLights,
+            Subcommand::SetHomeLight(vec![0x0f, 0xf0]),
+            Subcommand::EnableImu(0x01),
+            Subcommand::ImuSensitivity { gyro: 0x03, accel: 0x00, gyro_rate: 0x00, accel_filter: 0x01 },
+            Subcommand::WriteImuRegister { addr: 0x10, value: 0x60 },
+            Subcommand::ReadImuRegister { addr: 0x0f, len: 0x01 },
+            Subcommand::EnableVibration(0x01),
+            Subcommand::GetVoltage,
+            Subcommand::Unknown { id: 0x7f, data: vec![0x01] },
+        ];
+        for subcommand in subcommands {
+            let report = OutputReport::Subcommand { timer: 0x03, rumble: NEUTRAL_RUMBLE, subcommand: subcommand.clone() };
+            let buf = report.encode();
+            assert_eq!(buf.len(), REPORT_SIZE);
+            match OutputReport::parse(&buf).unwrap() {
+                OutputReport::Subcommand { timer, rumble, subcommand: parsed } => {
+                    assert_eq!((timer, rumble), (0x03, NEUTRAL_RUMBLE));
+                    assert_eq!(parsed.id(), subcommand.id());
+                    // variable length payloads come back with the padding
+                    assert_eq!(OutputReport::Subcommand { timer, rumble, subcommand: parsed }.encode(), buf);
+                }
+                other => panic!("expected a subcommand, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn input_reports_round_trip() {
+        let reports = [
+            InputReport::Reply { timer: 1, input: [0x8e; INPUT_SIZE], reply: Reply::with_data(0x02, vec![0x03, 0x48, 0x03, 0x02]) },
+            InputReport::Standard { timer: 2, input: [0x11; INPUT_SIZE], imu: [0x22; IMU_SIZE] },
+            InputReport::Nfc { timer: 3, input: [0x11; INPUT_SIZE], imu: [0x22; IMU_SIZE], mcu: Box::new([0x33; MCU_DATA_SIZE]) },
+            InputReport::Simple { buttons: [0x01, 0x20], hat: 0x08, sticks: [0x8000, 0x7fff, 0x0000, 0xffff] },
+        ];
+        for report in reports {
+            let buf = report.encode().unwrap();
+            assert_eq!(InputReport::parse(&buf).unwrap().encode().unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn malformed_reports() {
+        assert_eq!(OutputReport::parse(&[]), Err(ProtocolError::Empty));
+        assert_eq!(OutputReport::parse(&[0x42, 0x00]), Err(ProtocolError::UnknownReport(0x42)));
+        assert_eq!(OutputReport::parse(&[0x10, 0x00, 0x00]), Err(ProtocolError::Truncated { id: 0x10, len: 3, need: 10 }));
+        let too_large = InputReport::Reply { timer: 0, input: [0; INPUT_SIZE], reply: Reply::with_data(0x10, vec![0; MAX_REPLY_DATA + 1]) };
+        assert!(matches!(too_large.encode(), Err(ProtocolError::PayloadTooLarge { id: 0x10, .. })));
+    }
+}
@@ -0,0 +1,134 @@
+use mocopi_parser::Rotation;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Self = Self { w: 1.0, x: 0.0, y: 0.0, z: 0.0 };
+
+    pub fn new(w: f64, x: f64, y: f64, z: f64) -> Self {
+        Self { w, x, y, z }
+    }
+
+    // rotation of `angle` radians around the axis `v`, whose length is ignored
+    pub fn from_axis_angle(v: (f64, f64, f64), angle: f64) -> Self {
+        let n = (v.0 * v.0 + v.1 * v.1 + v.2 * v.2).sqrt();
+        if n == 0.0 {
+            return Self::IDENTITY;
+        }
+        let s = (angle / 2.0).sin() / n;
+        Self::new((angle / 2.0).cos(), v.0 * s, v.1 * s, v.2 * s)
+    }
+
+    pub fn conjugate(&self) -> Self {
+        Self::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub fn mul(&self, o: &Self) -> Self {
+        Self::new(
+            self.w * o.w - self.x * o.x - self.y * o.y - self.z * o.z,
+            self.w * o.x + self.x * o.w + self.y * o.z - self.z * o.y,
+            self.w * o.y - self.x * o.z + self.y * o.w + self.z * o.x,
+            self.w * o.z + self.x * o.y - self.y * o.x + self.z * o.w,
+        )
+    }
+
+    pub fn rotate(&self, v: (f64, f64, f64)) -> (f64, f64, f64) {
+        let p = Self::new(0.0, v.0, v.1, v.2);
+        let r = self.mul(&p).mul(&self.conjugate());
+        (r.x, r.y, r.z)
+    }
+
+    // axis scaled by the rotation angle in radians, taking the shortest path
+    pub fn rotation_vector(&self) -> (f64, f64, f64) {
+        let q = if self.w < 0.0 {
+            Self::new(-self.w, -self.x, -self.y, -self.z)
+        } else {
+            *self
+        };
+        let s = (q.x * q.x + q.y * q.y + q.z * q.z).sqrt();
+        if s < 1e-9 {
+            return (2.0 * q.x, 2.0 * q.y, 2.0 * q.z);
+        }
+        let angle = 2.0 * s.atan2(q.w);
+        (q.x / s * angle, q.y / s * angle, q.z / s * angle)
+    }
+
+    pub fn norm(&self) -> f64 {
+        (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn normalize(&self) -> Self {
+        let n = self.norm();
+        if n == 0.0 {
+            return Self::IDENTITY;
+        }
+        Self::new(self.w / n, self.x / n, self.y / n, self.z / n)
+    }
+
+    // rotation angle in radians, always in 0..=PI
+    pub fn angle(&self) -> f64 {
+        2.0 * self.normalize().w.abs().min(1.0).acos()
+    }
+
+    // roll (x), pitch (y) and yaw (z) in radians
+    pub fn euler(&self) -> (f64, f64, f64) {
+        let q = self.normalize();
+        let roll = (2.0 * (q.w * q.x + q.y * q.z)).atan2(1.0 - 2.0 * (q.x * q.x + q.y * q.y));
+        let pitch = (2.0 * (q.w * q.y - q.z * q.x)).clamp(-1.0, 1.0).asin();
+        let yaw = (2.0 * (q.w * q.z + q.x * q.y)).atan2(1.0 - 2.0 * (q.y * q.y + q.z * q.z));
+        (roll, pitch, yaw)
+    }
+}
+
+impl From<&Rotation> for Quaternion {
+    fn from(rot: &Rotation) -> Self {
+        Self::new(rot.w as f64, rot.x as f64, rot.y as f64, rot.z as f64).normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn close(a: (f64, f64, f64), b: (f64, f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9 && (a.2 - b.2).abs() < 1e-9
+    }
+
+    #[test]
+    fn rotate() {
+        // a quarter turn around z takes x to y
+        let q = Quaternion::from_axis_angle((0.0, 0.0, 2.0), FRAC_PI_2);
+        assert!(close(q.rotate((1.0, 0.0, 0.0)), (0.0, 1.0, 0.0)));
+        assert!(close(q.conjugate().rotate((0.0, 1.0, 0.0)), (1.0, 0.0, 0.0)));
+        assert!(close(q.mul(&q).rotate((1.0, 0.0, 0.0)), (-1.0, 0.0, 0.0)));
+        assert_eq!(Quaternion::from_axis_angle((0.0, 0.0, 0.0), 1.0), Quaternion::IDENTITY);
+    }
+
+    #[test]
+    fn rotation_vector() {
+        let v = (0.1, -0.2, 0.3);
+        let angle = (0.01f64 + 0.04 + 0.09).sqrt();
+        assert!(close(Quaternion::from_axis_angle(v, angle).rotation_vector(), v));
+        // the same rotation with the opposite sign takes the short way too
+        let q = Quaternion::from_axis_angle(v, angle);
+        assert!(close(Quaternion::new(-q.w, -q.x, -q.y, -q.z).rotation_vector(), v));
+        assert!(close(Quaternion::IDENTITY.rotation_vector(), (0.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn angles() {
+        let q = Quaternion::from_axis_angle((1.0, 0.0, 0.0), 0.5);
+        assert!((q.angle() - 0.5).abs() < 1e-9);
+        assert!(close(q.euler(), (0.5, 0.0, 0.0)));
+        assert!(close(Quaternion::from_axis_angle((0.0, 1.0, 0.0), -0.3).euler(), (0.0, -0.3, 0.0)));
+        assert!(close(Quaternion::from_axis_angle((0.0, 0.0, 1.0), 2.0).euler(), (0.0, 0.0, 2.0)));
+        assert_eq!(Quaternion::new(2.0, 0.0, 0.0, 0.0).normalize(), Quaternion::IDENTITY);
+    }
+}
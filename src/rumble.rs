@@ -0,0 +1,212 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use crate::protocol::RUMBLE_SIZE;
+
+// events a slow subscriber can fall behind by before it misses some
+const EVENT_CAPACITY: usize = 256;
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct RumbleConfig {
+    // print every change of the rumble the host asks for
+    pub log: bool,
+}
+
+// one linear resonant actuator, frequencies in Hz, amplitudes from 0 to 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rumble {
+    pub high_freq: f64,
+    pub high_amp: f64,
+    pub low_freq: f64,
+    pub low_amp: f64,
+}
+
+impl Rumble {
+    // the 4 byte HD rumble encoding of one side, the rarely used multi-pulse forms are read as the plain one
+    pub fn decode(data: &[u8; 4]) -> Self {
+        // high band: 7 bit frequency spread over byte 0 and bit 0 of byte 1, amplitude in the rest of byte 1
+        let high_code = ((u16::from(data[1] & 0x01) << 8) | u16::from(data[0])) >> 2;
+        let high_amp = data[1] >> 1;
+        // low band: 7 bit frequency in byte 2, amplitude in byte 3 with its lowest bit in bit 7 of byte 2
+        let low_code = data[2] & 0x7f;
+        let low_amp = (data[3].saturating_sub(0x40) << 1) | (data[2] >> 7);
+
+        Self {
+            high_freq: frequency(0x60 + high_code),
+            high_amp: amplitude(high_amp),
+            low_freq: frequency(0x40 + u16::from(low_code)),
+            low_amp: amplitude(low_amp),
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.high_amp == 0.0 && self.low_amp == 0.0
+    }
+}
+
+// 10Hz doubled every 32 steps
+fn frequency(code: u16) -> f64 {
+    10.0 * 2f64.powf(f64::from(code) / 32.0)
+}
+
+// the encoded amplitude is logarithmic above 0.12 and close to linear below
+fn amplitude(code: u8) -> f64 {
+    let code = f64::from(code.min(100));
+    let amp = if code >= 32.0 {
+        2f64.powf(code / 32.0) / 8.7
+    } else if code >= 16.0 {
+        2f64.powf(code / 16.0) / 17.0
+    } else {
+        code / 16.0 * 2.0 / 17.0
+    };
+    amp.min(1.0)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RumbleEvent {
+    // since the controller started
+    pub time: Duration,
+    pub left: Rumble,
+    pub right: Rumble,
+}
+
+// publishes the rumble of output reports 0x01, 0x10 and 0x11 whenever it changes
+#[derive(Clone)]
+pub struct RumbleEvents {
+    sender: broadcast::Sender<RumbleEvent>,
+    last: Arc<Mutex<Option<[u8; RUMBLE_SIZE]>>>,
+}
+
+impl RumbleEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            sender,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RumbleEvent> {
+        self.sender.subscribe()
+    }
+
+    // the host repeats the same rumble in every report, only changes become events
+    pub fn publish(&self, time: Duration, data: &[u8; RUMBLE_SIZE]) {
+        {
+            let mut last = self.last.lock().unwrap();
+            if last.as_ref() == Some(data) {
+                return;
+            }
+            *last = Some(*data);
+        }
+
+        let event = RumbleEvent {
+            time,
+            left: Rumble::decode(data[..4].try_into().unwrap()),
+            right: Rumble::decode(data[4..].try_into().unwrap()),
+        };
+        // nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+}
+
+// print every rumble event
+pub fn start_logging(events: &RumbleEvents) {
+    let mut receiver = events.subscribe();
+    tokio::task::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(e) if e.left.is_silent() && e.right.is_silent() => println!("Rumble at {:?}: off", e.time),
+                Ok(e) => println!(
+                    "Rumble at {:?}: left {:.0}Hz {:.2} / {:.0}Hz {:.2}, right {:.0}Hz {:.2} / {:.0}Hz {:.2}",
+                    e.time,
+                    e.left.high_freq,
+                    e.left.high_amp,
+                    e.left.low_freq,
+                    e.left.low_amp,
+                    e.right.high_freq,
+                    e.right.high_amp,
+                    e.right.low_freq,
+                    e.right.low_amp,
+                ),
+                Err(broadcast::error::RecvError::Lagged(n)) => println!("Rumble log missed {} events", n),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.005
+    }
+
+    #[test]
+    fn neutral_encoding() {
+        let r = Rumble::decode(&[0x00, 0x01, 0x40, 0x40]);
+        assert_eq!(r, Rumble { high_freq: 320.0, high_amp: 0.0, low_freq: 160.0, low_amp: 0.0 });
+        assert!(r.is_silent());
+    }
+
+    #[test]
+    fn half_amplitude() {
+        let r = Rumble::decode(&[0x00, 0x89, 0x40, 0x62]);
+        assert_eq!((r.high_freq, r.low_freq), (320.0, 160.0));
+        assert!(close(r.high_amp, 0.5) && close(r.low_amp, 0.5), "{:?}", r);
+        assert!(!r.is_silent());
+    }
+
+    #[test]
+    fn frequencies() {
+        // the lowest and highest codes of both bands
+        let r = Rumble::decode(&[0x00, 0x00, 0x00, 0x40]);
+        assert!(close(r.high_freq, 80.0) && close(r.low_freq, 40.0), "{:?}", r);
+        let r = Rumble::decode(&[0xfc, 0x01, 0x7f, 0x40]);
+        assert!((r.high_freq - 1253.0).abs() < 1.0 && (r.low_freq - 626.5).abs() < 0.5, "{:?}", r);
+        // the lowest bit of the low amplitude sits in byte 2
+        let r = Rumble::decode(&[0x00, 0x01, 0xc0, 0x40]);
+        assert_eq!(r.low_freq, 160.0);
+        assert!(close(r.low_amp, 1.0 / 17.0 / 8.0), "{:?}", r);
+    }
+
+    #[test]
+    fn amplitude_curve() {
+        assert_eq!(amplitude(0), 0.0);
+        // linear up to 2/17, then logarithmic without a jump
+        assert!(close(amplitude(8), 1.0 / 17.0));
+        assert_eq!(amplitude(16), 2.0 / 17.0);
+        assert!(amplitude(15) < amplitude(16) && amplitude(16) - amplitude(15) < 0.01);
+        assert!(amplitude(17) > amplitude(16) && amplitude(17) - amplitude(16) < 0.01);
+        assert_eq!(amplitude(32), 2.0 / 8.7);
+        assert!((1..=100).all(|c| amplitude(c) > amplitude(c - 1)));
+        assert_eq!(amplitude(100), 1.0);
+        assert_eq!(amplitude(0x7f), 1.0);
+    }
+
+    #[test]
+    fn only_changes_are_published() {
+        let events = RumbleEvents::new();
+        let mut receiver = events.subscribe();
+        let quiet = [0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40];
+        let buzz = [0x00, 0x89, 0x40, 0x62, 0x00, 0x01, 0x40, 0x40];
+
+        events.publish(Duration::from_millis(8), &quiet);
+        events.publish(Duration::from_millis(16), &quiet);
+        events.publish(Duration::from_millis(24), &buzz);
+        events.publish(Duration::from_millis(32), &buzz);
+
+        let first = receiver.try_recv().unwrap();
+        assert_eq!(first.time, Duration::from_millis(8));
+        assert!(first.left.is_silent() && first.right.is_silent());
+        let second = receiver.try_recv().unwrap();
+        assert_eq!(second.time, Duration::from_millis(24));
+        assert!(!second.left.is_silent() && second.right.is_silent());
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
@@ -0,0 +1,394 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use crate::calibration::{self, Calibration};
+use crate::identity::{DeviceType, Identity};
+use crate::protocol::{InputReport, OutputReport, ProtocolError, Reply, Subcommand, UsbCommand, RUMBLE_SIZE};
+
+// the limits are measured on the wall clock of a possibly busy machine,
+// so they are looser than what the console accepts to keep the tests from flaking
+// how long the console waits for a reply before it gives up
+const REPLY_TIMEOUT: Duration = Duration::from_millis(300);
+// gap between two input reports that the console still accepts
+const REPORT_TIMEOUT: Duration = Duration::from_millis(300);
+// the timer byte advances every 5ms, allow for 50ms of scheduling noise
+const TIMER_TICK: Duration = Duration::from_millis(5);
+const TIMER_TOLERANCE: i64 = 10;
+// how long the console stays asleep, stragglers queued before the suspend may still arrive
+const SUSPEND_TIME: Duration = Duration::from_millis(100);
+const STRAGGLER_TIME: Duration = Duration::from_millis(50);
+const NEUTRAL_RUMBLE: [u8; RUMBLE_SIZE] = [0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40];
+// 320Hz and 160Hz at about half strength on both sides
+const SAMPLE_RUMBLE: [u8; RUMBLE_SIZE] = [0x00, 0x89, 0x40, 0x62, 0x00, 0x89, 0x40, 0x62];
+
+// one end of an in-memory duplex link, every write is delivered as one report
+#[derive(Clone)]
+pub struct Pipe {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+pub fn duplex() -> (Pipe, Pipe) {
+    let (a_tx, a_rx) = unbounded();
+    let (b_tx, b_rx) = unbounded();
+    (Pipe { tx: a_tx, rx: b_rx }, Pipe { tx: b_tx, rx: a_rx })
+}
+
+impl Read for Pipe {
+    // behaves like a non-blocking hidg device
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.rx.try_recv() {
+            Ok(report) => {
+                let n = report.len().min(buf.len());
+                buf[..n].copy_from_slice(&report[..n]);
+                Ok(n)
+            }
+            Err(TryRecvError::Empty) => Err(ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => Ok(0),
+        }
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SimulatorError {
+    Timeout(String),
+    Malformed(ProtocolError),
+    Unexpected { expected: String, got: String },
+    Nack(u8),
+}
+
+impl fmt::Display for SimulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(what) => write!(f, "timed out waiting for {}", what),
+            Self::Malformed(e) => write!(f, "malformed report: {}", e),
+            Self::Unexpected { expected, got } => write!(f, "expected {}, got {}", expected, got),
+            Self::Nack(id) => write!(f, "subcommand {:02X} was not acknowledged", id),
+        }
+    }
+}
+
+impl Error for SimulatorError {}
+
+impl From<ProtocolError> for SimulatorError {
+    fn from(e: ProtocolError) -> Self {
+        Self::Malformed(e)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub replies: usize,
+    pub input_reports: usize,
+    pub slowest_reply: Duration,
+    pub longest_gap: Duration,
+}
+
+// plays the console's side of the handshake
+pub struct Host {
+    pipe: Pipe,
+    identity: Identity,
+    timer: u8,
+    last_report: Option<(Instant, u8)>,
+    simple_reports: usize,
+    // the stick calibration read from flash
+    calibration: Option<Calibration>,
+    summary: Summary,
+}
+
+impl Host {
+    pub fn new(pipe: Pipe, identity: Identity) -> Self {
+        Self {
+            pipe,
+            identity,
+            timer: 0,
+            last_report: None,
+            simple_reports: 0,
+            calibration: None,
+            summary: Summary::default(),
+        }
+    }
+
+    pub fn run(mut self, input_reports: usize) -> Result<Summary, SimulatorError> {
+        // the controller announces itself when it starts
+        self.expect_usb(0x03)?;
+        self.expect_usb(0x01)?;
+
+        self.handshake()?;
+
+        let info = self.subcommand(Subcommand::DeviceInfo)?;
+        if info.ack != 0x82 || info.data.get(..12) != Some(&self.identity.device_info()[..]) {
+            return Err(SimulatorError::Unexpected {
+                expected: format!("device info {:02X?}", self.identity.device_info()),
+                got: format!("{:02X} {:02X?}", info.ack, info.data),
+            });
+        }
+
+        self.subcommand(Subcommand::SetShipmentMode(0x00))?;
+        for (addr, len) in [(0x6000, 0x10), (0x6050, 0x0d)] {
+            self.spi_read(addr, len)?;
+        }
+        self.subcommand(Subcommand::SetReportMode(0x30))?;
+        let user = self.spi_read(0x8010, 0x18)?;
+        let factory = self.spi_read(0x603d, 0x19)?;
+        self.calibration = Some(Calibration::parse(&user, &factory));
+        for (addr, len) in [(0x6080, 0x18), (0x8028, 0x18), (0x6020, 0x18)] {
+            self.spi_read(addr, len)?;
+        }
+        self.subcommand(Subcommand::EnableImu(0x01))?;
+        self.subcommand(Subcommand::EnableVibration(0x01))?;
+        self.subcommand(Subcommand::SetPlayerLights(0x01))?;
+        self.subcommand(Subcommand::SetHomeLight(vec![0x0f, 0xf0, 0x00]))?;
+
+        self.stream(input_reports / 4)?;
+        // rumble on its own doesn't get an answer
+        self.timer = self.timer.wrapping_add(1) & 0x0f;
+        self.send(OutputReport::Rumble { timer: self.timer, rumble: SAMPLE_RUMBLE })?;
+        self.stream(input_reports / 2 - input_reports / 4)?;
+
+        // the test screens switch to the other report modes and back
+        let simple_reports = self.simple_reports;
+        self.subcommand(Subcommand::SetReportMode(0x3f))?;
+        self.expect_simple(simple_reports)?;
+        self.subcommand(Subcommand::SetReportMode(0x31))?;
+        self.expect_mode(0x31)?;
+        self.subcommand(Subcommand::SetReportMode(0x30))?;
+        self.expect_mode(0x30)?;
+
+        // the console goes to sleep and wakes up again
+        self.send(OutputReport::Usb(UsbCommand::DisableForceUsb))?;
+        thread::sleep(STRAGGLER_TIME);
+        while self.pipe.rx.try_recv().is_ok() {}
+        if let Ok(buf) = self.pipe.rx.recv_timeout(SUSPEND_TIME) {
+            return Err(SimulatorError::Unexpected {
+                expected: "no reports while suspended".to_string(),
+                got: format!("{:02X?}", buf.first()),
+            });
+        }
+        self.last_report = None;
+        self.handshake()?;
+        self.stream(input_reports - input_reports / 2)?;
+
+        Ok(self.summary)
+    }
+
+    fn handshake(&mut self) -> Result<(), SimulatorError> {
+        let status = self.usb(UsbCommand::Status)?;
+        if status.get(..8) != Some(&self.identity.status()[..]) {
+            return Err(SimulatorError::Unexpected {
+                expected: format!("status {:02X?}", self.identity.status()),
+                got: format!("{:02X?}", status),
+            });
+        }
+        self.usb(UsbCommand::Handshake)?;
+        self.usb(UsbCommand::HighSpeed)?;
+        self.usb(UsbCommand::Handshake)?;
+        self.send(OutputReport::Usb(UsbCommand::ForceUsb))
+    }
+
+    // the first report after switching to 0x3f, later ones only come when the input changes
+    fn expect_simple(&mut self, before: usize) -> Result<(), SimulatorError> {
+        let started = Instant::now();
+        while self.simple_reports == before {
+            if started.elapsed() > REPORT_TIMEOUT {
+                return Err(SimulatorError::Timeout("0x3F report".to_string()));
+            }
+            self.receive("0x3F report")?;
+        }
+        // 0x3f has no timer, the next one starts over
+        self.last_report = None;
+        Ok(())
+    }
+
+    // input reports queued before the switch may still arrive
+    fn expect_mode(&mut self, id: u8) -> Result<(), SimulatorError> {
+        let started = Instant::now();
+        while started.elapsed() < REPORT_TIMEOUT {
+            let report = self.receive(&format!("{:02X} report", id))?;
+            if report.id() == id {
+                return Ok(());
+            }
+        }
+        Err(SimulatorError::Timeout(format!("{:02X} report", id)))
+    }
+
+    fn stream(&mut self, count: usize) -> Result<(), SimulatorError> {
+        let end = self.summary.input_reports + count;
+        while self.summary.input_reports < end {
+            let report = self.receive("input report")?;
+            let input = match report {
+                InputReport::Standard { input, .. } => input,
+                _ => {
+                    return Err(SimulatorError::Unexpected {
+                        expected: "0x30 report".to_string(),
+                        got: format!("{:02X}", report.id()),
+                    });
+                }
+            };
+
+            // nobody touches the sticks, they have to be exactly centred for the console
+            if let Some(c) = self.calibration {
+                let left = calibration::unpack_stick(&input[4..7]);
+                let right = calibration::unpack_stick(&input[7..10]);
+                let device = self.identity.device_type;
+                let left_off = device != DeviceType::Right && c.left.decode(left.0, left.1) != (0.0, 0.0);
+                let right_off = device != DeviceType::Left && c.right.decode(right.0, right.1) != (0.0, 0.0);
+                if left_off || right_off {
+                    return Err(SimulatorError::Unexpected {
+                        expected: format!("sticks at {:?} and {:?}", c.left.center, c.right.center),
+                        got: format!("{:?} and {:?}", left, right),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, report: OutputReport) -> Result<(), SimulatorError> {
+        self.pipe
+            .write_all(&report.encode())
+            .map_err(|_| SimulatorError::Timeout("the controller to accept a report".to_string()))
+    }
+
+    // wait for the next report, checking the timing of input reports on the way
+    fn receive(&mut self, what: &str) -> Result<InputReport, SimulatorError> {
+        let timeout = REPORT_TIMEOUT.max(REPLY_TIMEOUT);
+        let buf = match self.pipe.rx.recv_timeout(timeout) {
+            Ok(buf) => buf,
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                return Err(SimulatorError::Timeout(what.to_string()));
+            }
+        };
+
+        let report = InputReport::parse(&buf)?;
+        if let InputReport::Simple { .. } = report {
+            self.simple_reports += 1;
+        }
+        if let InputReport::Standard { timer, .. } | InputReport::Nfc { timer, .. } = report {
+            let now = Instant::now();
+            if let Some((last, last_timer)) = self.last_report {
+                let gap = now - last;
+                self.summary.longest_gap = self.summary.longest_gap.max(gap);
+                if gap > REPORT_TIMEOUT {
+                    return Err(SimulatorError::Timeout(format!("input report after {:?}", gap)));
+                }
+
+                let ticks = (gap.as_nanos() / TIMER_TICK.as_nanos()) as i64;
+                let advanced = i64::from(timer.wrapping_sub(last_timer));
+                if (advanced - ticks).abs() > TIMER_TOLERANCE {
+                    return Err(SimulatorError::Unexpected {
+                        expected: format!("timer to advance by about {} after {:?}", ticks, gap),
+                        got: format!("{}", advanced),
+                    });
+                }
+            }
+            self.last_report = Some((now, timer));
+            self.summary.input_reports += 1;
+        }
+
+        Ok(report)
+    }
+
+    fn expect_usb(&mut self, command: u8) -> Result<Vec<u8>, SimulatorError> {
+        match self.receive(&format!("0x81 {:02X}", command))? {
+            InputReport::Usb { command: c, data } if c == command => Ok(data),
+            other => Err(SimulatorError::Unexpected {
+                expected: format!("0x81 {:02X}", command),
+                got: format!("{:02X?}", other),
+            }),
+        }
+    }
+
+    fn usb(&mut self, command: UsbCommand) -> Result<Vec<u8>, SimulatorError> {
+        self.send(OutputReport::Usb(command))?;
+        let started = Instant::now();
+        let data = self.expect_usb(command.id())?;
+        self.summary.slowest_reply = self.summary.slowest_reply.max(started.elapsed());
+        self.summary.replies += 1;
+        Ok(data)
+    }
+
+    fn subcommand(&mut self, subcommand: Subcommand) -> Result<Reply, SimulatorError> {
+        let id = subcommand.id();
+        self.timer = self.timer.wrapping_add(1) & 0x0f;
+        self.send(OutputReport::Subcommand { timer: self.timer, rumble: NEUTRAL_RUMBLE, subcommand })?;
+
+        let started = Instant::now();
+        loop {
+            if started.elapsed() > REPLY_TIMEOUT {
+                return Err(SimulatorError::Timeout(format!("reply to {:02X}", id)));
+            }
+
+            // input reports keep coming while the console waits
+            if let InputReport::Reply { reply, .. } = self.receive(&format!("reply to {:02X}", id))? {
+                if reply.id != id {
+                    return Err(SimulatorError::Unexpected {
+                        expected: format!("reply to {:02X}", id),
+                        got: format!("reply to {:02X}", reply.id),
+                    });
+                }
+                if reply.ack & 0x80 == 0 {
+                    return Err(SimulatorError::Nack(id));
+                }
+
+                self.summary.slowest_reply = self.summary.slowest_reply.max(started.elapsed());
+                self.summary.replies += 1;
+                return Ok(reply);
+            }
+        }
+    }
+
+    fn spi_read(&mut self, addr: u32, len: u8) -> Result<Vec<u8>, SimulatorError> {
+        let reply = self.subcommand(Subcommand::SpiRead { addr, len })?;
+
+        let mut header = addr.to_le_bytes().to_vec();
+        header.push(len);
+        if reply.ack != 0x90 || reply.data.get(..5) != Some(&header[..]) || reply.data.len() < 5 + usize::from(len) {
+            return Err(SimulatorError::Unexpected {
+                expected: format!("SPI read of {:05X} {:02X}", addr, len),
+                got: format!("{:02X} {:02X?}", reply.ack, reply.data),
+            });
+        }
+
+        Ok(reply.data[5..5 + usize::from(len)].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::identity::{DeviceType, Identity};
+
+    // the whole scripted handshake, then input reports
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn handshake_with_the_emulated_controller() {
+        let summary = crate::simulate(&Config::default()).await.unwrap();
+        assert!(summary.replies >= 18, "{:?}", summary);
+        assert!(summary.input_reports >= crate::SIMULATED_REPORTS, "{:?}", summary);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn handshake_as_a_joycon() {
+        let config = Config {
+            identity: Identity { device_type: DeviceType::Left, ..Identity::default() },
+            ..Config::default()
+        };
+        crate::simulate(&config).await.unwrap();
+    }
+}
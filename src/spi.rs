@@ -0,0 +1,234 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+pub const FLASH_SIZE: usize = 0x80000;
+pub const SECTOR_SIZE: usize = 0x1000;
+// the host never asks for more than fits into one subcommand reply
+pub const MAX_READ: usize = 0x1d;
+
+// factory configuration and calibration
+const FACTORY: (usize, [u8; 176]) = (0x6000, [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0x03, 0xa0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0xff, 0xff, 0xff, 0xff,
+    0xf0, 0xff, 0x89, 0x00, 0xf0, 0x01, 0x00, 0x40, 0x00, 0x40, 0x00, 0x40, 0xf9, 0xff, 0x06, 0x00,
+    0x09, 0x00, 0xe7, 0x3b, 0xe7, 0x3b, 0xe7, 0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xba, 0x15, 0x62,
+    0x11, 0xb8, 0x7f, 0x29, 0x06, 0x5b, 0xff, 0xe7, 0x7e, 0x0e, 0x36, 0x56, 0x9e, 0x85, 0x60, 0xff,
+    0x32, 0x32, 0x32, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x50, 0xfd, 0x00, 0x00, 0xc6, 0x0f, 0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14, 0x54, 0x41,
+    0x15, 0x54, 0xc7, 0x79, 0x9c, 0x33, 0x36, 0x63, 0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14,
+    0x54, 0x41, 0x15, 0x54, 0xc7, 0x79, 0x9c, 0x33, 0x36, 0x63, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+]);
+
+// user calibration, only the IMU has been calibrated
+const USER: (usize, [u8; 64]) = (0x8000, [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xb2, 0xa1, 0xbe, 0xff, 0x3e, 0x00, 0xf0, 0x01, 0x00, 0x40,
+    0x00, 0x40, 0x00, 0x40, 0xfe, 0xff, 0xfe, 0xff, 0x08, 0x00, 0xe7, 0x3b, 0xe7, 0x3b, 0xe7, 0x3b,
+]);
+
+lazy_static! {
+    static ref DEFAULT_IMAGE: Vec<u8> = {
+        let mut data = vec![0xff; FLASH_SIZE];
+        data[FACTORY.0..FACTORY.0 + FACTORY.1.len()].copy_from_slice(&FACTORY.1);
+        data[USER.0..USER.0 + USER.1.len()].copy_from_slice(&USER.1);
+        data
+    };
+}
+
+pub fn default_contents(addr: u32, len: usize) -> Vec<u8> {
+    let start = addr as usize;
+    DEFAULT_IMAGE[start..start + len].to_vec()
+}
+
+#[derive(Debug)]
+pub enum FlashError {
+    OutOfBounds { addr: u32, len: usize },
+    TooLong { addr: u32, len: usize },
+    InvalidImage { len: usize },
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { addr, len } => {
+                write!(f, "SPI access out of bounds: {:#07X} + {:#X}", addr, len)
+            }
+            Self::TooLong { addr, len } => {
+                write!(f, "SPI read too long: {:#07X} + {:#X}, at most {:#X}", addr, len, MAX_READ)
+            }
+            Self::InvalidImage { len } => {
+                write!(f, "SPI image must be {:#X} bytes, got {:#X}", FLASH_SIZE, len)
+            }
+        }
+    }
+}
+
+impl Error for FlashError {}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct FlashConfig {
+    // image file, created from the defaults if it doesn't exist yet
+    pub path: Option<String>,
+}
+
+pub struct Flash {
+    data: Vec<u8>,
+    path: Option<PathBuf>,
+    // made from the defaults rather than loaded from an image
+    new: bool,
+}
+
+impl Flash {
+    pub fn new() -> Self {
+        Self {
+            data: DEFAULT_IMAGE.clone(),
+            path: None,
+            new: true,
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+
+        if !path.exists() {
+            let flash = Self {
+                data: DEFAULT_IMAGE.clone(),
+                path: Some(path),
+                new: true,
+            };
+            flash.persist()?;
+            return Ok(flash);
+        }
+
+        let data = fs::read(&path)?;
+        if data.len() != FLASH_SIZE {
+            return Err(Box::new(FlashError::InvalidImage { len: data.len() }));
+        }
+
+        Ok(Self {
+            data,
+            path: Some(path),
+            new: false,
+        })
+    }
+
+    pub fn from_config(config: &FlashConfig) -> Result<Self, Box<dyn Error>> {
+        match &config.path {
+            Some(path) => Self::open(path),
+            None => Ok(Self::new()),
+        }
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.new
+    }
+
+    pub fn read(&self, addr: u32, len: usize) -> Result<&[u8], FlashError> {
+        if len > MAX_READ {
+            return Err(FlashError::TooLong { addr, len });
+        }
+        let range = Self::range(addr, len)?;
+        Ok(&self.data[range])
+    }
+
+    pub fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        let range = Self::range(addr, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        self.persist()?;
+        Ok(())
+    }
+
+    // several writes saved to the image once, none happen if any is out of bounds
+    pub fn write_batch(&mut self, writes: &[(u32, Vec<u8>)]) -> Result<(), Box<dyn Error>> {
+        let ranges = writes
+            .iter()
+            .map(|(addr, buf)| Self::range(*addr, buf.len()))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (range, (_, buf)) in ranges.into_iter().zip(writes) {
+            self.data[range].copy_from_slice(buf);
+        }
+        self.persist()?;
+        Ok(())
+    }
+
+    // erase the 4KiB sector containing `addr`
+    pub fn erase(&mut self, addr: u32) -> Result<(), Box<dyn Error>> {
+        let start = addr as usize / SECTOR_SIZE * SECTOR_SIZE;
+        let range = Self::range(start as u32, SECTOR_SIZE)?;
+        self.data[range].fill(0xff);
+        self.persist()?;
+        Ok(())
+    }
+
+    fn range(addr: u32, len: usize) -> Result<std::ops::Range<usize>, FlashError> {
+        let start = addr as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= FLASH_SIZE => Ok(start..end),
+            _ => Err(FlashError::OutOfBounds { addr, len }),
+        }
+    }
+
+    // written next to the image and renamed over it, so a crash never leaves half an image
+    fn persist(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(".tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(&self.data)?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_is_saved_and_reopened() {
+        let path = std::env::temp_dir().join(format!("flash-{}.bin", std::process::id()));
+        let mut flash = Flash::open(&path).unwrap();
+        flash.write(0x6001, &[0x12, 0x34]).unwrap();
+        flash.erase(0x8abc).unwrap();
+        flash.write_batch(&[(0x6010, vec![0x56]), (0x7000, vec![0x78, 0x9a])]).unwrap();
+
+        let reopened = Flash::open(&path).unwrap();
+        assert_eq!(reopened.read(0x6000, 3).unwrap(), [0xff, 0x12, 0x34]);
+        assert_eq!(reopened.read(0x6010, 1).unwrap(), [0x56]);
+        assert_eq!(reopened.read(0x7000, 2).unwrap(), [0x78, 0x9a]);
+        // the user calibration sector is erased
+        assert_eq!(reopened.read(0x8026, 2).unwrap(), [0xff, 0xff]);
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batch_out_of_bounds_writes_nothing() {
+        let mut flash = Flash::new();
+        let error = flash.write_batch(&[(0x6000, vec![0x00]), (FLASH_SIZE as u32 - 1, vec![0x00, 0x00])]);
+        assert!(error.is_err());
+        assert_eq!(flash.read(0x6000, 1).unwrap(), [0xff]);
+    }
+
+    #[test]
+    fn read_limits() {
+        let flash = Flash::new();
+        assert_eq!(flash.read(0x6000, MAX_READ).unwrap().len(), MAX_READ);
+        assert!(matches!(flash.read(0x6000, MAX_READ + 1), Err(FlashError::TooLong { addr: 0x6000, len: 0x1e })));
+        assert!(matches!(flash.read(FLASH_SIZE as u32 - 1, 2), Err(FlashError::OutOfBounds { .. })));
+        assert_eq!(
+            FlashError::TooLong { addr: 0x6000, len: 0x1e }.to_string(),
+            "SPI read too long: 0x06000 + 0x1E, at most 0x1D"
+        );
+    }
+}
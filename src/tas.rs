@@ -0,0 +1,291 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::{Input, InputField};
+use crate::arbiter::Arbiter;
+
+// stick coordinates in the scripts go from -STICK_MAX to STICK_MAX
+const STICK_MAX: f64 = 32767.0;
+
+// the key names of the nx-TAS format
+const KEYS: &[(&str, InputField)] = &[
+    ("KEY_A", InputField::A),
+    ("KEY_B", InputField::B),
+    ("KEY_X", InputField::X),
+    ("KEY_Y", InputField::Y),
+    ("KEY_LSTICK", InputField::StickLPress),
+    ("KEY_RSTICK", InputField::StickRPress),
+    ("KEY_L", InputField::L),
+    ("KEY_R", InputField::R),
+    ("KEY_ZL", InputField::Zl),
+    ("KEY_ZR", InputField::Zr),
+    ("KEY_PLUS", InputField::Plus),
+    ("KEY_MINUS", InputField::Minus),
+    ("KEY_DLEFT", InputField::Left),
+    ("KEY_DUP", InputField::Up),
+    ("KEY_DRIGHT", InputField::Right),
+    ("KEY_DDOWN", InputField::Down),
+    ("KEY_HOME", InputField::Home),
+    ("KEY_CAPTURE", InputField::Capture),
+    ("KEY_SL", InputField::Sl),
+    ("KEY_SR", InputField::Sr),
+];
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct TasConfig {
+    // script played from the first full input report on, one frame per report
+    pub play: Option<String>,
+    // every report's input written out as a script
+    pub record: Option<String>,
+}
+
+// `<frame> <keys> <lx>;<ly> <rx>;<ry>` for one frame, keys separated by `;` or NONE
+fn parse_line(line: &str) -> Result<(u64, Input), Box<dyn Error>> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (frame, keys, sticks) = match fields.as_slice() {
+        [frame, keys, sticks @ ..] if sticks.len() <= 2 => (frame.parse()?, keys, sticks),
+        _ => return Err("expected frame, keys and sticks".into()),
+    };
+
+    let mut input = Input::new();
+    for key in keys.split(';').filter(|k| !k.is_empty() && *k != "NONE") {
+        let (_, field) = KEYS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .ok_or_else(|| format!("unknown key {}", key))?;
+        input.set(*field, 1.0);
+    }
+    // a missing stick stays centered
+    let axes = [(InputField::StickLX, InputField::StickLY), (InputField::StickRX, InputField::StickRY)];
+    for (stick, (x_field, y_field)) in sticks.iter().zip(axes) {
+        let (x, y) = stick.split_once(';').ok_or("expected x;y")?;
+        input.set(x_field, (x.parse::<f64>()? / STICK_MAX).clamp(-1.0, 1.0));
+        input.set(y_field, (y.parse::<f64>()? / STICK_MAX).clamp(-1.0, 1.0));
+    }
+    Ok((frame, input))
+}
+
+fn format_line(frame: u64, input: &Input) -> String {
+    let keys: Vec<&str> = KEYS.iter().filter(|(_, field)| input.get(*field) != 0.0).map(|(name, _)| *name).collect();
+    let keys = if keys.is_empty() { "NONE".to_string() } else { keys.join(";") };
+    let coord = |v: f64| (v.clamp(-1.0, 1.0) * STICK_MAX).round() as i32;
+    format!(
+        "{} {} {};{} {};{}",
+        frame,
+        keys,
+        coord(input.stick_l.x),
+        coord(input.stick_l.y),
+        coord(input.stick_r.x),
+        coord(input.stick_r.y),
+    )
+}
+
+// frames missing from a script hold nothing
+struct Player {
+    frames: Vec<(u64, Input)>,
+    next: usize,
+    frame: u64,
+    input: Arc<Mutex<Input>>,
+}
+
+impl Player {
+    fn load(path: &str, input: Arc<Mutex<Input>>) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let mut frames: Vec<(u64, Input)> = vec![];
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (frame, i) = parse_line(line).map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+            if frames.last().is_some_and(|(last, _)| *last >= frame) {
+                return Err(format!("{} line {}: frame {} is out of order", path, number + 1, frame).into());
+            }
+            frames.push((frame, i));
+        }
+        println!("Loaded {} TAS frames from {}", frames.len(), path);
+        Ok(Self { frames, next: 0, frame: 0, input })
+    }
+
+    // the input of the next frame, false after the last one
+    fn tick(&mut self) -> bool {
+        let mut input = self.input.lock().unwrap();
+        match self.frames.get(self.next) {
+            Some((frame, i)) if *frame == self.frame => {
+                *input = i.clone();
+                self.next += 1;
+            }
+            Some(_) => *input = Input::new(),
+            None => {
+                *input = Input::new();
+                return false;
+            }
+        }
+        self.frame += 1;
+        true
+    }
+}
+
+struct Recorder {
+    out: BufWriter<File>,
+    frame: u64,
+}
+
+impl Recorder {
+    // like the scripts, frames with nothing held are left out
+    fn record(&mut self, input: &Input) {
+        if !input.is_neutral() {
+            let line = format_line(self.frame, input);
+            if let Err(e) = writeln!(self.out, "{}", line).and_then(|_| self.out.flush()) {
+                println!("Failed to record TAS input: {}", e);
+            }
+        }
+        self.frame += 1;
+    }
+}
+
+// TAS playback and recording, both one frame per full input report
+#[derive(Clone)]
+pub struct Tas {
+    // None once the script is done
+    player: Arc<Mutex<Option<Player>>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    arbiter: Arbiter,
+}
+
+impl Tas {
+    pub fn new(config: &TasConfig, arbiter: &Arbiter) -> Result<Self, Box<dyn Error>> {
+        let player = match &config.play {
+            Some(path) => {
+                let player = Player::load(path, arbiter.register("tas"))?;
+                // nothing else gets through until the script is done
+                arbiter.set_active("tas", true);
+                Some(player)
+            }
+            None => None,
+        };
+        let recorder = match &config.record {
+            Some(path) => {
+                let out = BufWriter::new(File::create(path).map_err(|e| format!("failed to create {}: {}", path, e))?);
+                Some(Arc::new(Mutex::new(Recorder { out, frame: 0 })))
+            }
+            None => None,
+        };
+        Ok(Self { player: Arc::new(Mutex::new(player)), recorder, arbiter: arbiter.clone() })
+    }
+
+    // the script's next frame onto the tas input source
+    pub fn tick(&self) {
+        let mut player = self.player.lock().unwrap();
+        if player.as_mut().is_some_and(|p| !p.tick()) {
+            println!("TAS script finished");
+            self.arbiter.set_active("tas", false);
+            *player = None;
+        }
+    }
+
+    // what this report sends
+    pub fn record(&self, input: &Input) {
+        if let Some(r) = &self.recorder {
+            r.lock().unwrap().record(input);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbiter::ArbiterConfig;
+
+    const SCRIPT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/script.txt");
+
+    #[test]
+    fn nx_tas_line_round_trip() {
+        let line = "12 KEY_A;KEY_ZR 0;32767 -32767;0";
+        let (frame, input) = parse_line(line).unwrap();
+        assert_eq!(frame, 12);
+        assert_eq!(input.get(InputField::A), 1.0);
+        assert_eq!(input.get(InputField::Zr), 1.0);
+        assert_eq!(input.get(InputField::B), 0.0);
+        assert_eq!((input.stick_l.x, input.stick_l.y), (0.0, 1.0));
+        assert_eq!((input.stick_r.x, input.stick_r.y), (-1.0, 0.0));
+        assert_eq!(format_line(frame, &input), line);
+
+        // lowercase keys and missing sticks
+        let (_, input) = parse_line("3 key_b;KEY_DUP").unwrap();
+        assert_eq!(format_line(3, &input), "3 KEY_B;KEY_DUP 0;0 0;0");
+        let (_, input) = parse_line("4 NONE 16384;-16384").unwrap();
+        assert_eq!(format_line(4, &input), "4 NONE 16384;-16384 0;0");
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert!(parse_line("12").is_err());
+        assert!(parse_line("x KEY_A 0;0 0;0").is_err());
+        assert!(parse_line("12 KEY_Q 0;0 0;0").is_err());
+        assert!(parse_line("12 KEY_A 0,0 0;0").is_err());
+        assert!(parse_line("12 KEY_A 0;0 0;0 0;0").is_err());
+    }
+
+    #[test]
+    fn unlisted_frames_are_neutral() {
+        let arbiter = Arbiter::new(&ArbiterConfig::default());
+        let tas = Tas::new(&TasConfig { play: Some(SCRIPT.to_string()), record: None }, &arbiter).unwrap();
+        let frames: Vec<Input> = (0..16)
+            .map(|_| {
+                tas.tick();
+                arbiter.combine()
+            })
+            .collect();
+
+        for (frame, input) in frames.iter().enumerate() {
+            match frame {
+                0 | 1 => assert_eq!(format_line(0, input), "0 KEY_A 0;0 0;0"),
+                4 => assert_eq!(format_line(4, input), "4 KEY_B;KEY_ZL 0;32767 0;0"),
+                12 => assert_eq!(format_line(12, input), "12 KEY_A;KEY_ZR 0;32767 -32767;0"),
+                13 => assert_eq!(format_line(13, input), "13 NONE -16384;0 0;0"),
+                _ => assert!(input.is_neutral(), "frame {}", frame),
+            }
+        }
+        assert!(tas.player.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn other_sources_are_held_off_while_playing() {
+        let arbiter = Arbiter::new(&ArbiterConfig::default());
+        let keyboard = arbiter.register("keyboard");
+        let tas = Tas::new(&TasConfig { play: Some(SCRIPT.to_string()), record: None }, &arbiter).unwrap();
+        keyboard.lock().unwrap().set(InputField::X, 1.0);
+
+        // frame 2 is neutral but the script is still going
+        for _ in 0..3 {
+            tas.tick();
+        }
+        assert!(arbiter.combine().is_neutral());
+        for _ in 3..15 {
+            tas.tick();
+        }
+        assert_eq!(arbiter.combine().get(InputField::X), 1.0);
+    }
+
+    #[test]
+    fn recording_plays_back() {
+        let path = std::env::temp_dir().join(format!("tas-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let arbiter = Arbiter::new(&ArbiterConfig::default());
+        let player = Tas::new(&TasConfig { play: Some(SCRIPT.to_string()), record: Some(path.clone()) }, &arbiter).unwrap();
+        for _ in 0..16 {
+            player.tick();
+            player.record(&arbiter.combine());
+        }
+        drop(player);
+
+        let recorded = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // keys come out in the order of the table
+        let script = fs::read_to_string(SCRIPT).unwrap().replace("KEY_ZL;KEY_B", "KEY_B;KEY_ZL");
+        assert_eq!(recorded, script);
+    }
+}